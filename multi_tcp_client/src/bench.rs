@@ -0,0 +1,338 @@
+// Bench mode: put load on the server and measure how it copes
+//
+// `multi_tcp_client bench --connections 50 --rate 2000 --duration 30` opens the
+// connections, each with its own thread and session, and sends OPT1-OPT4 requests
+// on them for the given time, in the proportions of --mix. With --rate the total
+// request rate is spread evenly over the connections; a connection that falls behind
+// does not catch up in a burst, so the report shows the rate the server sustained.
+// Without --rate every connection sends its next request as soon as the reply is in.
+//
+// The report counts requests, error replies (rate limit) and connections the run
+// lost (refused, busy, dropped, no reply within REPLY_TIMEOUT), and gives the
+// throughput and the RTT percentiles over all answered requests.
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use multi_tcp_client::OptClient;
+use serde_json::json;
+
+use crate::config::{parse_value, Config};
+use crate::protocol::{Frame, OP_COUNT, OP_ERROR, OP_REPLY, OP_UPPER, OP_UPTIME, OP_WHOAMI};
+use crate::script::{EXIT_CONNECTION, EXIT_OK, EXIT_PROTOCOL, EXIT_USAGE};
+
+pub const USAGE: &str = "\
+Usage: multi_tcp_client [options] bench [bench options]
+
+Bench options:
+  --connections <n> Concurrent connections (default 10)
+  --rate <n>        Requests per second over all connections, 0 for as fast as the
+                    server answers (default 0)
+  --duration <s>    Seconds to send requests for (default 10)
+  --mix <weights>   Share of each request, e.g. upper=4,count=1; requests left out
+                    are not sent (default upper=1,uptime=1,whoami=1,count=1)";
+
+// A server that takes longer than this to answer a request has lost the connection
+const REPLY_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Text of the OPT1 requests
+const UPPER_TEXT: &str = "the quick brown fox jumps over the lazy dog";
+
+const MIX_NAMES: [(&str, u8); 4] = [("upper", OP_UPPER), ("uptime", OP_UPTIME), ("whoami", OP_WHOAMI), ("count", OP_COUNT)];
+
+struct Options {
+    connections: usize,
+    // Requests per second over all connections, 0 for no limit
+    rate: f64,
+    // Time between two requests of one connection that --rate asks for
+    interval: Option<Duration>,
+    duration: Duration,
+    // Opcode and weight of each request in the mix
+    mix: Vec<(u8, u32)>,
+}
+
+impl Options {
+    fn parse(args: &[String]) -> Result<Options, String> {
+        let mut options = Options {
+            connections: 10,
+            rate: 0.0,
+            interval: None,
+            duration: Duration::from_secs(10),
+            mix: MIX_NAMES.iter().map(|&(_, opcode)| (opcode, 1)).collect(),
+        };
+        let mut args = args.iter().cloned();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--connections" => options.connections = parse_value(&arg, args.next())?,
+                "--rate" => options.rate = parse_value(&arg, args.next())?,
+                "--duration" => options.duration = parse_secs(&arg, args.next())?,
+                "--mix" => options.mix = parse_mix(&parse_value::<String>(&arg, args.next())?)?,
+                "-h" | "--help" => return Err(USAGE.to_string()),
+                other => return Err(format!("Unknown bench option: {}\n\n{}", other, USAGE)),
+            }
+        }
+        if options.connections == 0 {
+            return Err("--connections must be greater than 0".to_string());
+        }
+        if !options.rate.is_finite() || options.rate < 0.0 {
+            return Err("--rate must be 0 or more".to_string());
+        }
+        if options.duration.is_zero() {
+            return Err("--duration must be greater than 0".to_string());
+        }
+        if options.rate > 0.0 {
+            let interval = Duration::try_from_secs_f64(options.connections as f64 / options.rate)
+                .map_err(|_| format!("--rate is too low for {} connections", options.connections))?;
+            options.interval = Some(interval);
+        }
+        Ok(options)
+    }
+}
+
+// Seconds as a Duration; negative, infinite and too large values are refused
+fn parse_secs(flag: &str, value: Option<String>) -> Result<Duration, String> {
+    let secs: f64 = parse_value(flag, value)?;
+    if !secs.is_finite() || secs < 0.0 {
+        return Err(format!("Invalid value for {}: {}", flag, secs));
+    }
+    Duration::try_from_secs_f64(secs).map_err(|_| format!("Invalid value for {}: {}", flag, secs))
+}
+
+// "upper=4,count=1" -> [(OP_UPPER, 4), (OP_COUNT, 1)]
+fn parse_mix(spec: &str) -> Result<Vec<(u8, u32)>, String> {
+    let mut mix = Vec::new();
+    for part in spec.split(',') {
+        let (name, weight) = part.split_once('=').unwrap_or((part, "1"));
+        let opcode = MIX_NAMES
+            .iter()
+            .find(|(known, _)| *known == name.trim())
+            .map(|&(_, opcode)| opcode)
+            .ok_or_else(|| format!("--mix: unknown request '{}', use upper, uptime, whoami or count", name))?;
+        let weight: u32 = weight.trim().parse().map_err(|_| format!("--mix: invalid weight in '{}'", part))?;
+        if weight > 0 {
+            mix.push((opcode, weight));
+        }
+    }
+    if mix.is_empty() {
+        return Err("--mix needs at least one request with a weight above 0".to_string());
+    }
+    Ok(mix)
+}
+
+fn mix_name(opcode: u8) -> &'static str {
+    MIX_NAMES.iter().find(|&&(_, known)| known == opcode).map_or("?", |&(name, _)| name)
+}
+
+// What one connection saw
+#[derive(Default)]
+struct Tally {
+    replies: u64,
+    error_replies: u64,
+    rtts: Vec<Duration>,
+    // Why the connection ended before the time was up
+    lost: Option<String>,
+}
+
+// Run the bench described by the words after "bench"; returns the exit code
+pub fn run(server_addr: &str, config: &Config) -> i32 {
+    let options = match Options::parse(&config.command[1..]) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("{}", message);
+            return EXIT_USAGE;
+        }
+    };
+    // The order requests go out in: every opcode as often as its weight
+    let schedule: Vec<u8> = options.mix.iter().flat_map(|&(opcode, weight)| (0..weight).map(move |_| opcode)).collect();
+    let interval = options.interval;
+
+    // Open every connection before the clock starts, so connecting is not measured
+    let mut clients = Vec::with_capacity(options.connections);
+    let mut tallies = Vec::new();
+    for _ in 0..options.connections {
+        match OptClient::builder().config(config.clone()).reply_timeout(REPLY_TIMEOUT).connect(server_addr) {
+            Ok(client) => clients.push(client),
+            Err(e) => tallies.push(Tally { lost: Some(e.to_string()), ..Tally::default() }),
+        }
+    }
+    let Some(first) = clients.first() else {
+        eprintln!("{}", tallies[0].lost.as_deref().unwrap_or("no connection"));
+        return EXIT_CONNECTION;
+    };
+    if let Some(&(opcode, _)) = options.mix.iter().find(|&&(opcode, _)| !first.server().supports(opcode)) {
+        eprintln!("The server does not support '{}'", mix_name(opcode));
+        return EXIT_PROTOCOL;
+    }
+
+    let start_time = Instant::now();
+    let end_time = start_time + options.duration;
+    let workers: Vec<_> = clients
+        .into_iter()
+        .enumerate()
+        .map(|(index, client)| {
+            let schedule = schedule.clone();
+            // Connections start spread over one interval, not all at once
+            let first_send = start_time + interval.map_or(Duration::ZERO, |i| i * index as u32 / options.connections as u32);
+            thread::spawn(move || drive(client, &schedule, index, first_send, interval, end_time))
+        })
+        .collect();
+    tallies.extend(workers.into_iter().map(|worker| worker.join().unwrap()));
+    let elapsed = start_time.elapsed();
+
+    report(config, &options, &tallies, elapsed);
+    EXIT_OK
+}
+
+// Send requests on one connection until end_time
+fn drive(
+    mut client: OptClient,
+    schedule: &[u8],
+    offset: usize,
+    first_send: Instant,
+    interval: Option<Duration>,
+    end_time: Instant,
+) -> Tally {
+    let mut tally = Tally::default();
+    let mut next_send = first_send;
+    // Connections start at different points of the schedule, so at any moment the
+    // server sees the whole mix
+    for &opcode in schedule.iter().cycle().skip(offset % schedule.len()) {
+        let now = Instant::now();
+        if next_send >= end_time || now >= end_time {
+            break;
+        }
+        if next_send > now {
+            thread::sleep(next_send - now);
+        }
+        let request = if opcode == OP_UPPER { Frame::new(OP_UPPER, UPPER_TEXT) } else { Frame::empty(opcode) };
+        match client.request(request) {
+            Ok(response) => {
+                tally.replies += 1;
+                tally.rtts.push(response.rtt);
+                match response.frame.opcode {
+                    OP_REPLY => {}
+                    OP_ERROR => tally.error_replies += 1,
+                    other => {
+                        tally.lost = Some(format!("unexpected reply (opcode 0x{:02x})", other));
+                        return tally;
+                    }
+                }
+            }
+            Err(e) => {
+                tally.lost = Some(e.to_string());
+                return tally;
+            }
+        }
+        if let Some(interval) = interval {
+            next_send = (next_send + interval).max(Instant::now());
+        }
+    }
+    let _ = client.close();
+    tally
+}
+
+fn report(config: &Config, options: &Options, tallies: &[Tally], elapsed: Duration) {
+    let replies: u64 = tallies.iter().map(|tally| tally.replies).sum();
+    let error_replies: u64 = tallies.iter().map(|tally| tally.error_replies).sum();
+    // Why connections were lost, usually the same reason over and over
+    let mut reasons: Vec<(&str, usize)> = Vec::new();
+    for reason in tallies.iter().filter_map(|tally| tally.lost.as_deref()) {
+        match reasons.iter_mut().find(|(known, _)| *known == reason) {
+            Some((_, count)) => *count += 1,
+            None => reasons.push((reason, 1)),
+        }
+    }
+    let lost: usize = reasons.iter().map(|&(_, count)| count).sum();
+    let mut rtts: Vec<Duration> = tallies.iter().flat_map(|tally| tally.rtts.iter().copied()).collect();
+    rtts.sort_unstable();
+    let throughput = replies as f64 / elapsed.as_secs_f64();
+    let percentiles: Vec<(&str, f64)> = [("p50", 50.0), ("p90", 90.0), ("p99", 99.0), ("max", 100.0)]
+        .iter()
+        .map(|&(name, p)| (name, percentile(&rtts, p).as_micros() as f64 / 1000.0))
+        .collect();
+    let mix: Vec<String> = options.mix.iter().map(|&(opcode, weight)| format!("{}={}", mix_name(opcode), weight)).collect();
+
+    if config.json {
+        let rtt_ms: serde_json::Map<String, serde_json::Value> =
+            percentiles.iter().map(|&(name, ms)| (name.to_string(), json!(ms))).collect();
+        let report = json!({
+            "connections": options.connections,
+            "target_rate": options.rate,
+            "duration_s": elapsed.as_secs_f64(),
+            "mix": mix.join(","),
+            "requests": replies,
+            "error_replies": error_replies,
+            "connections_lost": lost,
+            "lost_reasons": reasons.iter().map(|&(reason, count)| json!({"reason": reason, "count": count})).collect::<Vec<_>>(),
+            "throughput": throughput,
+            "rtt_ms": rtt_ms,
+        });
+        println!("{}", report);
+        return;
+    }
+    let target = if options.rate > 0.0 { format!("{} req/s", options.rate) } else { "no limit".to_string() };
+    println!(
+        "{} connections for {:.1} s, target rate {}, mix {}",
+        options.connections,
+        elapsed.as_secs_f64(),
+        target,
+        mix.join(",")
+    );
+    println!("Requests: {} answered, {} with an error reply", replies, error_replies);
+    println!("Throughput: {:.1} req/s", throughput);
+    let rtt: Vec<String> = percentiles.iter().map(|(name, ms)| format!("{} {:.3}", name, ms)).collect();
+    println!("RTT (ms): {}", rtt.join(", "));
+    println!("Connections lost: {}", lost);
+    for (reason, count) in reasons {
+        println!("  {} x {}", count, reason);
+    }
+}
+
+// Nearest-rank percentile of sorted RTTs, zero when there are none
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Options, String> {
+        Options::parse(&args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn rate_sets_the_interval_per_connection() {
+        let options = parse(&["--connections", "4", "--rate", "100", "--duration", "0.5"]).unwrap();
+        assert_eq!(options.interval, Some(Duration::from_millis(40)));
+        assert_eq!(options.duration, Duration::from_millis(500));
+        assert_eq!(parse(&[]).unwrap().interval, None);
+    }
+
+    #[test]
+    fn bad_duration_is_refused() {
+        for value in ["-1", "inf", "NaN", "1e300", "soon"] {
+            assert!(parse(&["--duration", value]).is_err(), "--duration {}", value);
+        }
+        assert_eq!(parse(&["--duration", "0"]).err().unwrap(), "--duration must be greater than 0");
+    }
+
+    #[test]
+    fn bad_rate_is_refused() {
+        assert_eq!(parse(&["--rate", "-5"]).err().unwrap(), "--rate must be 0 or more");
+        assert!(parse(&["--rate", "1e-320"]).err().unwrap().contains("too low"));
+    }
+
+    #[test]
+    fn mix_weights() {
+        assert_eq!(parse_mix("upper=4, count").unwrap(), [(OP_UPPER, 4), (OP_COUNT, 1)]);
+        assert_eq!(parse_mix("upper=0,whoami=2").unwrap(), [(OP_WHOAMI, 2)]);
+        assert!(parse_mix("upper=0").is_err());
+        assert!(parse_mix("upper=x").is_err());
+        assert!(parse_mix("quit").is_err());
+    }
+}
@@ -0,0 +1,465 @@
+// OptClient: the OPT requests as typed methods
+//
+// One TCP connection (TLS if the config says so) that has been through the version
+// handshake. Requests go out one at a time and every method waits for its reply:
+//
+//     let mut client = OptClient::connect("localhost:11406")?;
+//     println!("{}", client.to_upper("hello")?);
+//     println!("up for {:?}, {} requests", client.uptime()?, client.request_count()?);
+//     client.close()?;
+//
+// The typed methods turn the reply text into a value and an error reply into
+// Error::Server. request() sends any frame and hands back the raw reply with its RTT;
+// pipeline() sends several without waiting and yields their replies in order.
+// Pushes that arrive while waiting are not printed: notices are kept for
+// take_notices(), a busy server ends the connection with Error::Busy.
+//
+// The interactive client only uses OptClient to connect: into_split() then hands
+// the two halves of the connection to its reader thread and menu.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::io::{self, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+use crate::protocol::{
+    check_hello, encode_frame, hello_request, read_frame, write_frame, Frame, Hello, OP_BUSY, OP_COUNT, OP_ERROR, OP_KICK, OP_NOTICE,
+    OP_QUIT, OP_REPLY, OP_RESUME, OP_SESSION, OP_SHUTDOWN, OP_UPPER, OP_UPTIME, OP_WHOAMI,
+};
+use crate::tls::{Connection, ReadHalf, WriteHalf};
+
+// Requests pipeline() keeps outstanding, as many as the server serves at once per
+// connection; more would only wait in the socket buffers
+const PIPELINE_DEPTH: usize = 32;
+
+#[derive(Debug)]
+pub enum Error {
+    // Connecting, TLS, reading or writing failed, or no reply within the reply timeout
+    Io(io::Error),
+    // The server is saturated and turned the connection away
+    Busy,
+    // The server closed the connection before the reply came
+    Closed,
+    // The version handshake failed, see check_hello
+    Handshake(String),
+    // The server did not list this request opcode in the handshake
+    Unsupported(u8),
+    // The server answered with an error reply (OP_ERROR)
+    Server(String),
+    // A reply this client cannot make sense of
+    Protocol(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Busy => write!(f, "the server is busy, try again later"),
+            Error::Closed => write!(f, "the server closed the connection"),
+            Error::Handshake(message) => write!(f, "cannot talk to this server: {}", message),
+            Error::Unsupported(opcode) => write!(f, "the server does not support opcode 0x{:02x}", opcode),
+            Error::Server(message) => write!(f, "error from server: {}", message),
+            Error::Protocol(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+// A raw reply to request()
+#[derive(Debug, Clone)]
+pub struct Response {
+    pub frame: Frame,
+    // From sending the request to having the whole reply
+    pub rtt: Duration,
+}
+
+pub struct OptClientBuilder {
+    config: Config,
+    reply_timeout: Option<Duration>,
+    resume: Option<String>,
+}
+
+impl OptClientBuilder {
+    // TLS settings come from the --tls* fields; the rest of the config is not used
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    // Give up on a reply after this long, with an Io error of kind WouldBlock or
+    // TimedOut. Without it a request waits as long as the connection is up.
+    pub fn reply_timeout(mut self, timeout: Duration) -> Self {
+        self.reply_timeout = Some(timeout);
+        self
+    }
+
+    // Ask for the session behind this resume token before the handshake, see
+    // OptClient::resumed. An unknown or expired token only means a new session.
+    pub fn resume(mut self, token: impl Into<String>) -> Self {
+        self.resume = Some(token.into());
+        self
+    }
+
+    pub fn connect(self, addr: impl ToSocketAddrs) -> Result<OptClient, Error> {
+        let socket = TcpStream::connect(addr)?;
+        socket.set_read_timeout(self.reply_timeout)?;
+        let stream = Connection::open(socket, &self.config)?;
+        let mut client = OptClient {
+            stream,
+            server: Hello { version: 0, opcodes: Vec::new() },
+            request_id: 0,
+            notices: Vec::new(),
+            shutting_down: false,
+            session_token: None,
+            resumed: None,
+        };
+        // The server only takes a resume request as the first one on the connection
+        if let Some(token) = self.resume {
+            let response = client.exchange(Frame::new(OP_RESUME, token))?;
+            client.resumed = Some(match response.frame.opcode {
+                OP_ERROR => Err(response.frame.text()),
+                _ => Ok(response.frame.text()),
+            });
+        }
+        let response = client.exchange(hello_request())?;
+        client.server = check_hello(&response.frame).map_err(Error::Handshake)?;
+        Ok(client)
+    }
+}
+
+pub struct OptClient {
+    stream: Connection,
+    server: Hello,
+    request_id: u32,
+    notices: Vec<String>,
+    shutting_down: bool,
+    session_token: Option<String>,
+    resumed: Option<Result<String, String>>,
+}
+
+impl OptClient {
+    pub fn builder() -> OptClientBuilder {
+        OptClientBuilder { config: Config::default(), reply_timeout: None, resume: None }
+    }
+
+    // Plain TCP with the default settings
+    pub fn connect(addr: impl ToSocketAddrs) -> Result<OptClient, Error> {
+        OptClient::builder().connect(addr)
+    }
+
+    // What the server said about itself in the handshake
+    pub fn server(&self) -> &Hello {
+        &self.server
+    }
+
+    // Resume token of this session, if the server sent one
+    pub fn session_token(&self) -> Option<&str> {
+        self.session_token.as_deref()
+    }
+
+    // How the resume request of OptClientBuilder::resume went: the server's reply,
+    // or why it started a new session instead. None without a resume request.
+    pub fn resumed(&self) -> Option<Result<&str, &str>> {
+        self.resumed.as_ref().map(|resumed| resumed.as_deref().map_err(String::as_str))
+    }
+
+    // The negotiated TLS version, None without TLS
+    pub fn tls_version(&self) -> Option<String> {
+        self.stream.tls_version()
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.socket().peer_addr()
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.stream.socket().local_addr()
+    }
+
+    // Operator notices received since the last call
+    pub fn take_notices(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.notices)
+    }
+
+    // The server announced its shutdown; replies to pending requests still come
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutting_down
+    }
+
+    // OPT1
+    pub fn to_upper(&mut self, text: &str) -> Result<String, Error> {
+        self.call(Frame::new(OP_UPPER, text))
+    }
+
+    // OPT2, to the second
+    pub fn uptime(&mut self) -> Result<Duration, Error> {
+        let reply = self.call(Frame::empty(OP_UPTIME))?;
+        parse_uptime(&reply).ok_or_else(|| unexpected("uptime", &reply))
+    }
+
+    // OPT3: this client's address as the server sees it
+    pub fn whoami(&mut self) -> Result<SocketAddr, Error> {
+        let reply = self.call(Frame::empty(OP_WHOAMI))?;
+        parse_whoami(&reply).ok_or_else(|| unexpected("whoami", &reply))
+    }
+
+    // OPT4: requests the server has served so far, over all clients
+    pub fn request_count(&mut self) -> Result<u64, Error> {
+        let reply = self.call(Frame::empty(OP_COUNT))?;
+        parse_count(&reply).ok_or_else(|| unexpected("count", &reply))
+    }
+
+    // OPT5: end the session, so the server does not keep it for resumption
+    pub fn close(mut self) -> Result<(), Error> {
+        write_frame(&mut self.stream, &Frame::empty(OP_QUIT))?;
+        self.stream.shutdown()?;
+        Ok(())
+    }
+
+    // Change the reply timeout of OptClientBuilder::reply_timeout; None waits as long
+    // as the connection is up
+    pub fn set_reply_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.stream.socket().set_read_timeout(timeout)
+    }
+
+    // Hand the connection over to a reader and a writer, e.g. separate threads.
+    // From then on pushes are the reader's to handle; the reply timeout stays on the
+    // socket, see set_reply_timeout. Take what is needed (server(), session_token(),
+    // notices) first.
+    pub fn into_split(self) -> Result<(ReadHalf, WriteHalf), Error> {
+        Ok(self.stream.split()?)
+    }
+
+    // Send any request the server offers and wait for its reply. An error reply is
+    // returned like any other; only what ends the exchange is an Err.
+    pub fn request(&mut self, request: Frame) -> Result<Response, Error> {
+        if !self.server.supports(request.opcode) {
+            return Err(Error::Unsupported(request.opcode));
+        }
+        self.exchange(request)
+    }
+
+    // Send the requests without waiting for each reply; the server may serve them
+    // concurrently. The replies come out in request order, every RTT from sending
+    // that request to having its reply. Nothing is sent if the server does not
+    // offer one of the requests.
+    pub fn pipeline(&mut self, requests: Vec<Frame>) -> Result<Pipeline<'_>, Error> {
+        if let Some(request) = requests.iter().find(|request| !self.server.supports(request.opcode)) {
+            return Err(Error::Unsupported(request.opcode));
+        }
+        Ok(Pipeline { client: self, unsent: requests.into_iter(), sent: VecDeque::new(), early: HashMap::new(), failed: false })
+    }
+
+    // The reply text of a request, or the error reply as Error::Server
+    fn call(&mut self, request: Frame) -> Result<String, Error> {
+        let response = self.request(request)?;
+        match response.frame.opcode {
+            OP_REPLY => Ok(response.frame.text()),
+            OP_ERROR => Err(Error::Server(response.frame.text())),
+            other => Err(Error::Protocol(format!("unexpected reply (opcode 0x{:02x})", other))),
+        }
+    }
+
+    fn exchange(&mut self, request: Frame) -> Result<Response, Error> {
+        let request = request.with_id(self.next_id());
+        let start_time = Instant::now();
+        write_frame(&mut self.stream, &request)?;
+        loop {
+            let frame = self.read_reply()?;
+            if frame.id == request.id {
+                return Ok(Response { frame, rtt: start_time.elapsed() });
+            }
+            // Reply to some other id
+        }
+    }
+
+    fn next_id(&mut self) -> u32 {
+        // 0 is reserved for pushes
+        self.request_id = self.request_id.wrapping_add(1).max(1);
+        self.request_id
+    }
+
+    // The next frame that is not a push; pushes on the way are taken care of
+    fn read_reply(&mut self) -> Result<Frame, Error> {
+        loop {
+            let frame = read_frame(&mut self.stream)?.ok_or(Error::Closed)?;
+            if !frame.is_push() {
+                return Ok(frame);
+            }
+            match frame.opcode {
+                // The connection closes right after a kick
+                OP_NOTICE | OP_KICK => self.notices.push(frame.text()),
+                OP_SESSION => self.session_token = Some(frame.text()),
+                OP_BUSY => return Err(Error::Busy),
+                // The reply may still come while the server drains its clients
+                OP_SHUTDOWN => self.shutting_down = true,
+                _ => {}
+            }
+        }
+    }
+}
+
+// Replies of OptClient::pipeline, in request order. Ends after the first error.
+pub struct Pipeline<'a> {
+    client: &'a mut OptClient,
+    unsent: std::vec::IntoIter<Frame>,
+    // Id and send time of every request that is still owed a reply, oldest first
+    sent: VecDeque<(u32, Instant)>,
+    // Replies that overtook an older request
+    early: HashMap<u32, Response>,
+    failed: bool,
+}
+
+impl Pipeline<'_> {
+    // See OptClient::take_notices
+    pub fn take_notices(&mut self) -> Vec<String> {
+        self.client.take_notices()
+    }
+
+    // See OptClient::is_shutting_down
+    pub fn is_shutting_down(&self) -> bool {
+        self.client.is_shutting_down()
+    }
+
+    fn next_response(&mut self) -> Result<Option<Response>, Error> {
+        loop {
+            // One write for all the requests that fit, so Nagle's algorithm does not
+            // hold the later ones back until the first is acknowledged
+            let mut bytes = Vec::new();
+            let mut ids = Vec::new();
+            while self.sent.len() + ids.len() < PIPELINE_DEPTH {
+                let Some(request) = self.unsent.next() else { break };
+                let request = request.with_id(self.client.next_id());
+                bytes.extend(encode_frame(&request)?);
+                ids.push(request.id);
+            }
+            if !bytes.is_empty() {
+                let sent_at = Instant::now();
+                self.client.stream.write_all(&bytes)?;
+                self.client.stream.flush()?;
+                self.sent.extend(ids.into_iter().map(|id| (id, sent_at)));
+            }
+            let Some(&(oldest, _)) = self.sent.front() else {
+                return Ok(None);
+            };
+            if let Some(response) = self.early.remove(&oldest) {
+                self.sent.pop_front();
+                return Ok(Some(response));
+            }
+            let frame = self.client.read_reply()?;
+            // Replies to ids this pipeline did not send are dropped
+            if let Some(&(id, sent_at)) = self.sent.iter().find(|(id, _)| *id == frame.id) {
+                self.early.insert(id, Response { frame, rtt: sent_at.elapsed() });
+            }
+        }
+    }
+}
+
+impl Iterator for Pipeline<'_> {
+    type Item = Result<Response, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let result = self.next_response();
+        self.failed = result.is_err();
+        result.transpose()
+    }
+}
+
+fn unexpected(command: &str, reply: &str) -> Error {
+    Error::Protocol(format!("unexpected {} reply: {}", command, reply))
+}
+
+// "run time = HH:MM:SS"; the hours may run past 99
+fn parse_uptime(reply: &str) -> Option<Duration> {
+    let mut parts = reply.strip_prefix("run time = ")?.split(':');
+    let hours: u64 = parts.next()?.parse().ok()?;
+    let minutes: u64 = parts.next()?.parse().ok()?;
+    let seconds: u64 = parts.next()?.parse().ok()?;
+    if parts.next().is_some() {
+        return None;
+    }
+    Some(Duration::from_secs(hours * 3600 + minutes * 60 + seconds))
+}
+
+// "client IP = 127.0.0.1, port = 53984"
+fn parse_whoami(reply: &str) -> Option<SocketAddr> {
+    let (ip, port) = reply.strip_prefix("client IP = ")?.split_once(", port = ")?;
+    Some(SocketAddr::new(ip.parse::<IpAddr>().ok()?, port.parse().ok()?))
+}
+
+// "requests served = 42"
+fn parse_count(reply: &str) -> Option<u64> {
+    reply.strip_prefix("requests served = ")?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn silent_server_times_out_with_a_reply_timeout() {
+        // Accepted by the kernel, never answered
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let start = Instant::now();
+        let result = OptClient::builder()
+            .reply_timeout(Duration::from_millis(200))
+            .connect(listener.local_addr().unwrap());
+        let Err(Error::Io(e)) = result else {
+            panic!("expected a timeout, got {:?}", result.map(|_| ()));
+        };
+        assert!(matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut), "{}", e);
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn uptime_reply_is_parsed() {
+        assert_eq!(parse_uptime("run time = 00:00:00"), Some(Duration::ZERO));
+        assert_eq!(parse_uptime("run time = 01:02:03"), Some(Duration::from_secs(3723)));
+        // The hours keep counting past 99
+        assert_eq!(parse_uptime("run time = 123:04:05"), Some(Duration::from_secs(123 * 3600 + 245)));
+    }
+
+    #[test]
+    fn malformed_uptime_reply_is_rejected() {
+        assert_eq!(parse_uptime("run time = 01:02:03:04"), None);
+        assert_eq!(parse_uptime("run time = 01:02"), None);
+        assert_eq!(parse_uptime("run time = 01:xx:03"), None);
+        assert_eq!(parse_uptime("01:02:03"), None);
+    }
+
+    #[test]
+    fn whoami_reply_is_parsed() {
+        assert_eq!(parse_whoami("client IP = 127.0.0.1, port = 53984"), Some("127.0.0.1:53984".parse().unwrap()));
+        assert_eq!(parse_whoami("client IP = ::1, port = 40000"), Some("[::1]:40000".parse().unwrap()));
+        assert_eq!(parse_whoami("client IP = 127.0.0.1, port = 70000"), None);
+        assert_eq!(parse_whoami("client IP = localhost, port = 53984"), None);
+        assert_eq!(parse_whoami("127.0.0.1:53984"), None);
+    }
+
+    #[test]
+    fn count_reply_is_parsed() {
+        assert_eq!(parse_count("requests served = 0"), Some(0));
+        assert_eq!(parse_count("requests served = 42"), Some(42));
+        assert_eq!(parse_count("requests served = -1"), None);
+        assert_eq!(parse_count("requests = 42"), None);
+    }
+}
@@ -0,0 +1,222 @@
+// Client settings, taken from command-line flags
+
+use std::env;
+use std::path::PathBuf;
+use std::time::Duration;
+
+pub const USAGE: &str = "\
+Usage: multi_tcp_client [options] [command]
+
+Without a command the interactive menu starts. A command sends its request, prints
+the reply and exits:
+  upper <text>      OPT1: the text in upper case
+  uptime            OPT2: how long the server has been running
+  whoami            OPT3: this client's IP and port as the server sees them
+  count             OPT4: requests the server has handled so far
+  who               OPT6: connected clients and their statistics
+  batch <file>      The commands in a file, one per line ('#' starts a comment)
+  bench [options]   Load the server with many connections and report throughput and
+                    RTT percentiles; see bench --help
+
+Exit codes of commands: 0 done, 1 the server answered with an error, 2 usage error,
+3 connection error (refused, TLS, busy, closed), 4 protocol error (handshake,
+command not supported by the server, unexpected reply)
+
+Options:
+  --server <host:port>
+                    Server to talk to (default localhost:11406)
+  --json            Commands: print one JSON object per request, with the RTT;
+                    bench: print the report as one JSON object
+  --udp             Talk to the server over UDP instead of TCP
+  --timeout <ms>    UDP: wait this long for a reply before retransmitting (default 1000)
+  --retries <n>     UDP: retransmissions before giving up on a request (default 3)
+  --resume <token>  TCP: take over a dropped session, using the token printed when it dropped
+  --reconnect       TCP menu: when the connection drops, connect again with growing
+                    delays and carry on with the same session
+  --reconnect-attempts <n>
+                    Attempts before --reconnect gives up (default 10)
+  --tls             TCP: encrypt the connection with TLS; needs --tls-ca
+  --tls-ca <path>   PEM file with the CA certificate(s) to check the server certificate against
+  --tls-server-name <name>
+                    Name the server certificate must be issued for (default the
+                    host of --server)
+  --tls-cert <path> PEM client certificate, for servers that require one; needs --tls-key
+  --tls-key <path>  PEM private key of the --tls-cert certificate
+  -h, --help        Show this message";
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    // host:port of the server, for every mode
+    pub server: String,
+    pub udp: bool,
+    pub timeout: Duration,
+    pub retries: u32,
+    pub resume: Option<String>,
+    pub reconnect: bool,
+    pub reconnect_attempts: u32,
+    pub tls: bool,
+    pub tls_ca: Option<PathBuf>,
+    pub tls_server_name: String,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub json: bool,
+    // Command and its arguments, empty for the interactive menu
+    pub command: Vec<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            server: "localhost:11406".to_string(),
+            udp: false,
+            timeout: Duration::from_millis(1000),
+            retries: 3,
+            resume: None,
+            reconnect: false,
+            reconnect_attempts: 10,
+            tls: false,
+            tls_ca: None,
+            tls_server_name: "localhost".to_string(),
+            tls_cert: None,
+            tls_key: None,
+            json: false,
+            command: Vec::new(),
+        }
+    }
+}
+
+impl Config {
+    pub fn from_args() -> Result<Config, String> {
+        Config::parse(env::args().skip(1))
+    }
+
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Config, String> {
+        let mut config = Config::default();
+        let mut server_name: Option<String> = None;
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--server" => {
+                    config.server = parse_value(&arg, args.next())?;
+                    server_host(&config.server)?;
+                }
+                "--udp" => config.udp = true,
+                "--timeout" => {
+                    let ms: u64 = parse_value(&arg, args.next())?;
+                    if ms == 0 {
+                        return Err("--timeout must be greater than 0".to_string());
+                    }
+                    config.timeout = Duration::from_millis(ms);
+                }
+                "--retries" => config.retries = parse_value(&arg, args.next())?,
+                "--resume" => config.resume = Some(parse_value(&arg, args.next())?),
+                "--reconnect" => config.reconnect = true,
+                "--reconnect-attempts" => {
+                    config.reconnect_attempts = parse_value(&arg, args.next())?;
+                    if config.reconnect_attempts == 0 {
+                        return Err("--reconnect-attempts must be greater than 0".to_string());
+                    }
+                }
+                "--tls" => config.tls = true,
+                "--tls-ca" => config.tls_ca = Some(parse_value(&arg, args.next())?),
+                "--tls-server-name" => server_name = Some(parse_value(&arg, args.next())?),
+                "--tls-cert" => config.tls_cert = Some(parse_value(&arg, args.next())?),
+                "--tls-key" => config.tls_key = Some(parse_value(&arg, args.next())?),
+                "--json" => config.json = true,
+                "-h" | "--help" => return Err(USAGE.to_string()),
+                other if other.starts_with("--") => return Err(format!("Unknown option: {}\n\n{}", other, USAGE)),
+                // The command takes the rest of the line, flags come before it
+                _ => {
+                    config.command.push(arg);
+                    config.command.extend(args.by_ref());
+                }
+            }
+        }
+        config.tls_server_name = match server_name {
+            Some(name) => name,
+            None => server_host(&config.server)?.to_string(),
+        };
+        config.check_tls()?;
+        if !config.command.is_empty() && (config.udp || config.resume.is_some()) {
+            return Err("Commands work over TCP with a new session, drop --udp and --resume".to_string());
+        }
+        if config.reconnect && (config.udp || !config.command.is_empty()) {
+            return Err("--reconnect is for the interactive menu over TCP".to_string());
+        }
+        Ok(config)
+    }
+
+    fn check_tls(&self) -> Result<(), String> {
+        if !self.tls {
+            if self.tls_ca.is_some() || self.tls_cert.is_some() || self.tls_key.is_some() {
+                return Err("The --tls-* options need --tls".to_string());
+            }
+            return Ok(());
+        }
+        if self.udp {
+            return Err("--tls works over TCP only, drop --udp".to_string());
+        }
+        if self.tls_ca.is_none() {
+            return Err("--tls needs --tls-ca <path> with the certificate(s) to trust".to_string());
+        }
+        if self.tls_cert.is_some() != self.tls_key.is_some() {
+            return Err("--tls-cert and --tls-key go together".to_string());
+        }
+        Ok(())
+    }
+}
+
+pub fn parse_value<T: std::str::FromStr>(flag: &str, value: Option<String>) -> Result<T, String> {
+    let value = value.ok_or_else(|| format!("Missing value for {}", flag))?;
+    value
+        .parse()
+        .map_err(|_| format!("Invalid value for {}: {}", flag, value))
+}
+
+// The host part of host:port, without the brackets of an IPv6 address
+fn server_host(server: &str) -> Result<&str, String> {
+    let invalid = || format!("Invalid value for --server: {} (expected host:port)", server);
+    let (host, port) = server.rsplit_once(':').ok_or_else(invalid)?;
+    port.parse::<u16>().map_err(|_| invalid())?;
+    let host = host.strip_prefix('[').and_then(|host| host.strip_suffix(']')).unwrap_or(host);
+    if host.is_empty() {
+        return Err(invalid());
+    }
+    Ok(host)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Config, String> {
+        Config::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn server_defaults_to_localhost() {
+        let config = parse(&[]).unwrap();
+        assert_eq!(config.server, "localhost:11406");
+        assert_eq!(config.tls_server_name, "localhost");
+    }
+
+    #[test]
+    fn server_host_names_the_tls_server() {
+        let config = parse(&["--server", "opt.example.net:9000", "count"]).unwrap();
+        assert_eq!(config.server, "opt.example.net:9000");
+        assert_eq!(config.tls_server_name, "opt.example.net");
+        assert_eq!(config.command, ["count"]);
+        let config = parse(&["--server", "[::1]:11406"]).unwrap();
+        assert_eq!(config.tls_server_name, "::1");
+        let config = parse(&["--tls-server-name", "opt", "--server", "10.0.0.5:11406"]).unwrap();
+        assert_eq!(config.tls_server_name, "opt");
+    }
+
+    #[test]
+    fn server_needs_a_port() {
+        for server in ["localhost", "localhost:", "localhost:http", ":11406", "localhost:70000"] {
+            assert!(parse(&["--server", server]).is_err(), "--server {}", server);
+        }
+        assert!(parse(&["--server"]).is_err());
+    }
+}
@@ -0,0 +1,17 @@
+// OPT client library
+//
+// The client side of the protocol behind the multi_tcp_client binary, for scripts,
+// tests and other tools:
+//
+//     let mut client = OptClient::connect("localhost:11406")?;
+//     let count = client.request_count()?;
+//     client.close()?;
+//
+// OptClient::builder() takes a Config for TLS and a reply timeout.
+
+pub mod client;
+pub mod config;
+pub mod protocol;
+pub mod tls;
+
+pub use client::{Error, OptClient, OptClientBuilder, Pipeline, Response};
@@ -1,188 +1,183 @@
 // SEUNG EON JIN 20201406
-use std::net::TcpStream;
-use std::io::{self, Read, Write};
-use std::str::from_utf8;
-use std::sync::{Arc, Mutex, atomic::{AtomicBool, Ordering}};
-use std::thread;
+mod bench;
+mod menu;
+mod reader;
+mod reconnect;
+mod script;
+mod stats;
+mod udp;
+
+use std::sync::{Arc, Mutex, atomic::Ordering};
 use std::time::Instant;
-use ctrlc;
 use std::process;
+use multi_tcp_client::{config, protocol, tls, Error, OptClient};
+use config::Config;
+use menu::Choice;
+use reader::{Event, Status};
+use reconnect::Reconnect;
+use stats::RttHistory;
+use protocol::{Frame, write_frame, OP_QUIT};
 
 fn main() -> std::io::Result<()> {
-    let server_ip = "localhost";
-    // let server_ip = "nsl5.cau.ac.kr";
-    let server_port = "11406";
-    let server_addr = format!("{}:{}", server_ip, server_port);
+    let config = match Config::from_args() {
+        Ok(config) => config,
+        Err(msg) => {
+            eprintln!("{}", msg);
+            process::exit(2);
+        }
+    };
+
+    let server_addr = config.server.clone();
+
+    if config.udp {
+        return udp::run(&server_addr, &config);
+    }
 
-    // Create TCP connection to server
-    let stream = TcpStream::connect(server_addr.clone())?;
+    if config.command.first().is_some_and(|command| command == "bench") {
+        process::exit(bench::run(&server_addr, &config));
+    }
+    if !config.command.is_empty() {
+        process::exit(script::run(&server_addr, &config));
+    }
+
+    // Connect (with TLS if --tls is set), take the session back with --resume and
+    // learn which commands the server offers, or give up
+    let mut builder = OptClient::builder().config(config.clone());
+    if let Some(token) = &config.resume {
+        builder = builder.resume(token.clone());
+    }
+    let mut client = builder.connect(server_addr.as_str()).unwrap_or_else(|e| {
+        match e {
+            Error::Busy => println!("Server is busy. Try again later."),
+            Error::Handshake(message) => println!("Cannot talk to this server: {}", message),
+            // Includes a failed TLS handshake, and a TLS 1.3 server that wants a client
+            // certificate, which turns us away only on the first request
+            e => println!("Cannot connect to {}: {}", server_addr, e),
+        }
+        process::exit(1);
+    });
 
     println!(
         "Connected to server at {} from client at {}",
-        stream.peer_addr().unwrap().to_string(),
-        stream.local_addr().unwrap().to_string()
+        client.peer_addr().unwrap(),
+        client.local_addr().unwrap()
     );
+    if let Some(version) = client.tls_version() {
+        println!("Connection encrypted with {}, server certificate verified", version);
+    }
+    menu::report_handshake(&mut client);
+    let server = client.server().clone();
+
+    // Id of the last request; replies carry the id of the request they answer.
+    // 0 is reserved for messages the server sends on its own.
+    let mut request_id: u32 = 0;
 
-    let stream_arc = Arc::new(Mutex::new(stream));
+    // State shared with the reader thread, which takes over reading once the menu starts
+    let status = Arc::new(Status::default());
+    *status.session_token.lock().unwrap() = client.session_token().map(str::to_string);
 
-    // Flag to notify server watcher thread to exit
-    let stop_flag = Arc::new(AtomicBool::new(false));
-    let stop_flag_for_server = Arc::clone(&stop_flag);
+    // From here on the reader thread reads every frame: it shows pushes as they come
+    // and hands replies over, the main thread only writes
+    let (read_half, write_half) = client.into_split().map_err(std::io::Error::other)?;
+    let writer = Arc::new(Mutex::new(write_half));
+    let reconnect = config.reconnect.then(|| Reconnect::new(&server_addr, &config, Arc::clone(&writer)));
+    let events = reader::spawn(read_half, Arc::clone(&status), reconnect);
 
     // Ctrl+C interrupt handling
     {
-        let stream_clone = Arc::clone(&stream_arc);
+        let writer = Arc::clone(&writer);
         ctrlc::set_handler(move || {
-            let _ = stream_clone.lock().unwrap().shutdown(std::net::Shutdown::Both);
+            let _ = writer.lock().unwrap().shutdown();
             println!("\nBye bye~");
             process::exit(0);
         }).expect("Error setting Ctrl-C handler");
     }
 
-    // Clone TcpStream for server watcher thread
-    let stream_for_server = {
-        let guard = stream_arc.lock().unwrap();
-        guard.try_clone().expect("Failed to clone TcpStream")
-    };
-
-    // Server watcher thread: monitors if the server disconnects
-    thread::spawn(move || {
-        let mut buf = [0; 1];
-        loop {
-            if stop_flag_for_server.load(Ordering::Relaxed) {
-                // Exit when stop flag is set
-                break;
-            }
-
-            let result = stream_for_server.peek(&mut buf);
-            match result {
-                Ok(0) => {
-                    println!("\nServer disconnected. Terminating.");
-                    process::exit(0);
-                }
-                Ok(_) => {
-                    // Server is alive, check again after a short sleep
-                    thread::sleep(std::time::Duration::from_millis(500));
-                }
-                Err(ref e) => {
-                    if stop_flag_for_server.load(Ordering::Relaxed) {
-                        // If shutdown initiated, ignore error and exit quietly
-                        break;
-                    }
-
-                    if let Some(code) = e.raw_os_error() {
-                        #[cfg(windows)]
-                        if code == 10053 || code == 10054 {
-                            break;
-                        }
-                        #[cfg(unix)]
-                        if code == 104 {
-                            break;
-                        }
-                    }
-
-                    // Unexpected error (not normal shutdown)
-                    println!("\nUnexpected connection error: {}", e);
-                    process::exit(1);
-                }
-            }
-        }
-    });
+    // RTT of every answered request, for menu entries 7 and 8
+    let mut history = RttHistory::default();
 
     // Main thread: User input handling
     loop {
-        // Print options
-        println!("\n--- Menu ---");
-        println!("1) Convert text to UPPER-case letters");
-        println!("2) Ask how long the server has been running for since server started (HH:MM:SS)");
-        println!("3) Ask what the IP and port of the client are");
-        println!("4) Ask how many requests the server has handled so far");
-        println!("5) Exit client program");
-
-        print!("Select option (1~5): ");
-        io::Write::flush(&mut io::stdout())?;
-
-        // Get option from user
-        let mut choice = String::new();
-        io::stdin().read_line(&mut choice)?;
-        let choice = choice.trim();
-
-        // Prepare message to send
-        let msg_to_send = match choice {
-            "1" => {
-                // Option 1: user provides additional text input
-                loop {
-                    print!("Enter text to convert to UPPER-case: ");
-                    io::Write::flush(&mut io::stdout())?;
-                    let mut input = String::new();
-                    if io::stdin().read_line(&mut input)? == 0 {
-                        println!("EOF : invalid input...");
-                        continue;
-                    }
-                    let trimmed = input.trim_end();
-                    if trimmed.chars().all(|c| c.is_ascii_alphabetic() || c.is_ascii_whitespace() || c.is_ascii_digit()) {
-                        break format!("OPT1{}", trimmed);
-                    } else {
-                        println!("Only English letters, numbers and spaces are allowed. Please try again.");
-                    }
-                }
-            }
-            // Options 2~4: simple header message
-            "2" => "OPT2".to_string(),
-            "3" => "OPT3".to_string(),
-            "4" => "OPT4".to_string(),
-            "5" => {
-                // Exit program
-                stop_flag.store(true, Ordering::Relaxed); // notify watcher thread
+        menu::print_menu(&server);
+        let request = match menu::read_choice(&server)? {
+            Choice::Request(frame) => frame,
+            Choice::Exit => {
+                // Exit program; the reader thread sees the connection close and ends quietly
+                status.stopping.store(true, Ordering::SeqCst);
 
-                let _ = stream_arc.lock().unwrap().write_all(b"OPT5");
-                let _ = stream_arc.lock().unwrap().shutdown(std::net::Shutdown::Both);
+                let mut writer = writer.lock().unwrap();
+                let _ = write_frame(&mut *writer, &Frame::empty(OP_QUIT));
+                let _ = writer.shutdown();
 
                 println!("Bye bye~");
                 break;
             }
-            _ => {
+            Choice::ShowStats => {
+                history.print();
+                continue;
+            }
+            Choice::ExportStats(path) => {
+                history.export(&path);
+                continue;
+            }
+            Choice::Invalid => {
                 println!("Invalid option. Try again.");
                 continue;
             }
         };
 
+        request_id = request_id.wrapping_add(1).max(1);
+        let request = request.with_id(request_id);
+
         // Check time before sending the command
+        status.awaiting_reply.store(true, Ordering::SeqCst);
         let start_time = Instant::now();
 
-        // Send message to server
-        let write_result = stream_arc.lock().unwrap().write_all(msg_to_send.as_bytes());
-        if let Err(e) = write_result {
-            if handle_io_error(&e, "Write") {
-                break;
-            }
+        // Send message to server, noting which connection it went out on
+        let (write_result, generation) = {
+            let mut writer = writer.lock().unwrap();
+            (write_frame(&mut *writer, &request), status.generation.load(Ordering::SeqCst))
+        };
+        // With --reconnect the reader thread deals with a lost connection, and the
+        // request counts as lost once the connection is back
+        if let Err(e) = write_result
+            && !config.reconnect
+            && handle_io_error(&e, "Write")
+        {
+            break;
         }
 
-        // Get response from server
-        let mut buffer = [0; 512];
-        let size = match stream_arc.lock().unwrap().read(&mut buffer) {
-            Ok(0) => {
-                println!("Server closed the connection.");
-                break;
-            }
-            Ok(n) => n,
-            Err(e) => {
-                if handle_io_error(&e, "Read") {
-                    break;
+        // Get response from the reader thread. Replies to earlier requests that
+        // arrive late are skipped; pushes never come this way.
+        let reply = loop {
+            match events.recv() {
+                Ok(Event::Reply(reply)) if reply.frame.id == request_id => break Some(reply),
+                Ok(Event::Reconnected) if status.generation.load(Ordering::SeqCst) != generation => {
+                    println!("The request was lost with the old connection. Please send it again.");
+                    break None;
                 }
-                0
+                Ok(_) => {}
+                // The reader thread ended: the server closed after announcing its shutdown
+                Err(_) => break None,
             }
         };
+        // Cleared before looking at `closing`, see reader::run
+        status.awaiting_reply.store(false, Ordering::SeqCst);
 
-        // Prevent failure of utf-8 decoding
-        let reply = from_utf8(&buffer[..size]).unwrap_or("[Invalid UTF-8 reply]");
+        if let Some(reply) = reply {
+            // RTT up to the moment the reader thread had the whole reply
+            let elapsed = reply.received_at.duration_since(start_time);
 
-        // Check RTT after receiving reply
-        let elapsed = start_time.elapsed();
+            // Print the reply and RTT
+            menu::print_reply(&reply.frame);
+            println!("RTT = {:.3} ms", elapsed.as_secs_f64() * 1000.0); // 1s = 1000ms
+            history.record(request.opcode, elapsed, 0);
+        }
 
-        // Print the reply and RTT
-        println!("Reply from server: {}", reply);
-        println!("RTT = {:.3} ms", elapsed.as_secs_f64() * 1000.0); // 1s = 1000ms
+        if status.closing.load(Ordering::SeqCst) {
+            println!("Bye bye~");
+            break;
+        }
     }
 
     Ok(())
@@ -0,0 +1,134 @@
+// Interactive menu shared by the TCP and UDP modes
+
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+use multi_tcp_client::OptClient;
+
+use crate::protocol::{Frame, Hello, OP_COUNT, OP_ERROR, OP_QUIT, OP_REPLY, OP_UPPER, OP_UPTIME, OP_WHO, OP_WHOAMI};
+
+pub enum Choice {
+    Request(Frame),
+    Exit,
+    // Entries the client handles itself
+    ShowStats,
+    ExportStats(PathBuf),
+    Invalid,
+}
+
+// Menu keys, the opcode each one sends and its label
+const ENTRIES: [(&str, u8, &str); 6] = [
+    ("1", OP_UPPER, "Convert text to UPPER-case letters"),
+    ("2", OP_UPTIME, "Ask how long the server has been running for since server started (HH:MM:SS)"),
+    ("3", OP_WHOAMI, "Ask what the IP and port of the client are"),
+    ("4", OP_COUNT, "Ask how many requests the server has handled so far"),
+    ("5", OP_QUIT, "Exit client program"),
+    ("6", OP_WHO, "List connected clients and their statistics"),
+];
+
+// Entries that need no server, always offered
+const LOCAL_ENTRIES: [(&str, &str); 2] = [
+    ("7", "Show RTT statistics of this session"),
+    ("8", "Export RTT history to a CSV file"),
+];
+
+// Whether a menu entry is offered: the server must support the command, except
+// exit, which always works
+fn available(server: &Hello, opcode: u8) -> bool {
+    opcode == OP_QUIT || server.supports(opcode)
+}
+
+// Entries for commands the server did not list in the handshake are left out
+pub fn print_menu(server: &Hello) {
+    println!("\n--- Menu ---");
+    for (key, opcode, label) in ENTRIES {
+        if available(server, opcode) {
+            println!("{}) {}", key, label);
+        }
+    }
+    for (key, label) in LOCAL_ENTRIES {
+        println!("{}) {}", key, label);
+    }
+}
+
+// Ask for an option (and the text for option 1) and turn it into a request frame
+pub fn read_choice(server: &Hello) -> io::Result<Choice> {
+    let keys: Vec<&str> = ENTRIES.iter()
+        .filter(|(_, opcode, _)| available(server, *opcode))
+        .map(|(key, _, _)| *key)
+        .chain(LOCAL_ENTRIES.iter().map(|(key, _)| *key))
+        .collect();
+    if keys.len() == ENTRIES.len() + LOCAL_ENTRIES.len() {
+        print!("Select option (1~8): ");
+    } else {
+        print!("Select option ({}): ", keys.join(", "));
+    }
+    io::stdout().flush()?;
+
+    // Get option from user
+    let mut choice = String::new();
+    io::stdin().read_line(&mut choice)?;
+
+    let choice = choice.trim();
+    if !keys.contains(&choice) {
+        return Ok(Choice::Invalid);
+    }
+    let request = match choice {
+        "1" => {
+            // Option 1: user provides additional text input
+            loop {
+                print!("Enter text to convert to UPPER-case: ");
+                io::stdout().flush()?;
+                let mut input = String::new();
+                if io::stdin().read_line(&mut input)? == 0 {
+                    println!("EOF : invalid input...");
+                    continue;
+                }
+                let trimmed = input.trim_end();
+                if trimmed.chars().all(|c| c.is_ascii_alphabetic() || c.is_ascii_whitespace() || c.is_ascii_digit()) {
+                    break Frame::new(OP_UPPER, trimmed);
+                } else {
+                    println!("Only English letters, numbers and spaces are allowed. Please try again.");
+                }
+            }
+        }
+        // Options 2~4 and 6: opcode only, empty payload
+        "2" => Frame::empty(OP_UPTIME),
+        "3" => Frame::empty(OP_WHOAMI),
+        "4" => Frame::empty(OP_COUNT),
+        "6" => Frame::empty(OP_WHO),
+        "5" => return Ok(Choice::Exit),
+        "7" => return Ok(Choice::ShowStats),
+        "8" => {
+            print!("CSV file to write (default rtt.csv): ");
+            io::stdout().flush()?;
+            let mut path = String::new();
+            io::stdin().read_line(&mut path)?;
+            let path = path.trim();
+            return Ok(Choice::ExportStats(PathBuf::from(if path.is_empty() { "rtt.csv" } else { path })));
+        }
+        _ => return Ok(Choice::Invalid),
+    };
+    Ok(Choice::Request(request))
+}
+
+pub fn print_reply(reply: &Frame) {
+    match reply.opcode {
+        OP_REPLY => println!("Reply from server: {}", reply.text()),
+        OP_ERROR => println!("Error from server: {}", reply.text()),
+        other => println!("Unexpected reply (opcode 0x{:02x}): {}", other, reply.text()),
+    }
+}
+
+// What happened while connecting: the outcome of --resume and the notices that
+// arrived before the reader thread took over
+pub fn report_handshake(client: &mut OptClient) {
+    match client.resumed() {
+        Some(Ok(reply)) => println!("Session {}.", reply),
+        Some(Err(reason)) => println!("Could not resume the session ({}). Starting a new one.", reason),
+        None => {}
+    }
+    for notice in client.take_notices() {
+        println!("Notice from server: {}", notice);
+    }
+}
@@ -0,0 +1,302 @@
+// Wire format of the OPT protocol
+//
+// Every message in either direction is one frame:
+//
+//   +-----------------+--------+-------------+-------------------+
+//   | length (u32 BE) | opcode | id (u32 BE) | payload (length)  |
+//   +-----------------+--------+-------------+-------------------+
+//
+// `length` counts payload bytes only, so an empty request is 9 bytes on the wire.
+// Frames are read with read_exact, which means a request may span several TCP
+// segments and several requests may share one segment.
+//
+// `id` is picked by the client for every request and echoed in the reply, so a client
+// can have several requests outstanding and match the replies, which may come back in
+// any order.
+//
+// Messages the server sends on its own rather than in reply to a request are pushes:
+// busy, shutdown, operator notices, the resume token and the operator's kick. They
+// are marked by id 0 (PUSH_ID), which clients must not use for requests, and may
+// arrive at any time, also between a request and its reply. A client reads them as
+// they come.
+//
+// Over UDP every datagram carries exactly one frame. The id doubles as sequence
+// number: a retransmitted request keeps its id, and the server answers a repeated id
+// with the reply it already sent instead of running the command twice.
+//
+// A client starts with an OP_HELLO request carrying its protocol version and the
+// request opcodes it knows (see Hello); the reply carries the same for the server.
+// Each side refuses a peer whose version is below its MIN_PROTOCOL_VERSION, and a
+// client only offers the commands the server listed. The handshake is optional for
+// the server: a client that skips it is served as before.
+
+use std::io::{self, Read, Write};
+
+// Request opcodes (client -> server)
+pub const OP_UPPER: u8 = 0x01; // OPT1
+pub const OP_UPTIME: u8 = 0x02; // OPT2
+pub const OP_WHOAMI: u8 = 0x03; // OPT3
+pub const OP_COUNT: u8 = 0x04; // OPT4
+pub const OP_QUIT: u8 = 0x05; // OPT5
+pub const OP_WHO: u8 = 0x06; // OPT6: connected clients and their statistics
+pub const OP_RESUME: u8 = 0x07; // first request on a connection: take over a session by its token
+pub const OP_HELLO: u8 = 0x08; // handshake: protocol version and supported opcodes
+
+// Reply opcodes (server -> client)
+pub const OP_REPLY: u8 = 0x80;
+pub const OP_ERROR: u8 = 0x81;
+pub const OP_BUSY: u8 = 0x82; // sent instead of serving when the server is saturated, then closed
+pub const OP_SHUTDOWN: u8 = 0x83; // unsolicited notice: the server is shutting down
+pub const OP_NOTICE: u8 = 0x84; // unsolicited notice from the server operator, text payload
+pub const OP_SESSION: u8 = 0x85; // unsolicited, right after connect: the session's resume token
+pub const OP_KICK: u8 = 0x86; // unsolicited: the operator disconnected this client, do not come back
+
+// Version of the protocol described here, and the oldest version a peer may speak
+pub const PROTOCOL_VERSION: u16 = 1;
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+
+// Payload of OP_HELLO and of its reply: the sender's protocol version (u16 BE)
+// followed by the request opcodes it supports, one byte each
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hello {
+    pub version: u16,
+    pub opcodes: Vec<u8>,
+}
+
+impl Hello {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut payload = self.version.to_be_bytes().to_vec();
+        payload.extend_from_slice(&self.opcodes);
+        payload
+    }
+
+    pub fn decode(payload: &[u8]) -> Option<Hello> {
+        let (version, opcodes) = payload.split_first_chunk::<2>()?;
+        Some(Hello { version: u16::from_be_bytes(*version), opcodes: opcodes.to_vec() })
+    }
+
+    pub fn supports(&self, opcode: u8) -> bool {
+        self.opcodes.contains(&opcode)
+    }
+}
+
+// Request opcodes this client may send, announced in the handshake
+const CLIENT_OPCODES: [u8; 8] = [OP_UPPER, OP_UPTIME, OP_WHOAMI, OP_COUNT, OP_QUIT, OP_WHO, OP_RESUME, OP_HELLO];
+
+// The handshake request of this client
+pub fn hello_request() -> Frame {
+    let hello = Hello { version: PROTOCOL_VERSION, opcodes: CLIENT_OPCODES.to_vec() };
+    Frame::new(OP_HELLO, hello.encode())
+}
+
+// Check the server's answer to hello_request; the error says why we cannot talk to it
+pub fn check_hello(reply: &Frame) -> Result<Hello, String> {
+    match reply.opcode {
+        OP_REPLY => {}
+        // What a server without the handshake answers to an opcode it does not know
+        OP_ERROR if reply.text() == "Invalid message." => {
+            return Err("the server predates the version handshake, update the server".to_string());
+        }
+        OP_ERROR => return Err(format!("the server refused protocol version {}: {}", PROTOCOL_VERSION, reply.text())),
+        other => return Err(format!("unexpected handshake reply (opcode 0x{:02x})", other)),
+    }
+    let server = Hello::decode(&reply.payload).ok_or_else(|| "malformed handshake reply".to_string())?;
+    if server.version < MIN_PROTOCOL_VERSION {
+        return Err(format!(
+            "the server speaks protocol version {}, this client needs at least version {}",
+            server.version, MIN_PROTOCOL_VERSION
+        ));
+    }
+    Ok(server)
+}
+
+// Upper bound for a single payload, protects the reader from bogus length headers
+pub const MAX_PAYLOAD: usize = 16 * 1024 * 1024;
+
+const HEADER_LEN: usize = 9;
+
+// Largest UDP payload that fits in one IPv4 datagram
+pub const MAX_DATAGRAM: usize = 65507;
+
+// Id of pushes, see the header comment
+pub const PUSH_ID: u32 = 0;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub id: u32,
+    pub opcode: u8,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new(opcode: u8, payload: impl Into<Vec<u8>>) -> Self {
+        Frame { id: 0, opcode, payload: payload.into() }
+    }
+
+    pub fn is_push(&self) -> bool {
+        self.id == PUSH_ID
+    }
+
+    // The same frame with another request id
+    pub fn with_id(mut self, id: u32) -> Self {
+        self.id = id;
+        self
+    }
+
+    pub fn empty(opcode: u8) -> Self {
+        Frame { id: 0, opcode, payload: Vec::new() }
+    }
+
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.payload).into_owned()
+    }
+}
+
+// Read one frame. Returns Ok(None) if the peer closed the connection cleanly
+// between two frames; a close in the middle of a frame is an UnexpectedEof error.
+pub fn read_frame<R: Read>(reader: &mut R) -> io::Result<Option<Frame>> {
+    let mut header = [0u8; HEADER_LEN];
+
+    // First byte separately so that a clean close can be told apart from a truncated frame
+    loop {
+        match reader.read(&mut header[..1]) {
+            Ok(0) => return Ok(None),
+            Ok(_) => break,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    reader.read_exact(&mut header[1..])?;
+
+    let length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    if length > MAX_PAYLOAD {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("frame payload of {} bytes exceeds limit of {} bytes", length, MAX_PAYLOAD),
+        ));
+    }
+
+    let mut payload = vec![0u8; length];
+    reader.read_exact(&mut payload)?;
+    Ok(Some(Frame { id: frame_id(&header), opcode: header[4], payload }))
+}
+
+// Encode a frame into a single buffer so it goes out with one write_all call
+pub fn encode_frame(frame: &Frame) -> io::Result<Vec<u8>> {
+    if frame.payload.len() > MAX_PAYLOAD {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("frame payload of {} bytes exceeds limit of {} bytes", frame.payload.len(), MAX_PAYLOAD),
+        ));
+    }
+    let mut buf = Vec::with_capacity(HEADER_LEN + frame.payload.len());
+    buf.extend_from_slice(&(frame.payload.len() as u32).to_be_bytes());
+    buf.push(frame.opcode);
+    buf.extend_from_slice(&frame.id.to_be_bytes());
+    buf.extend_from_slice(&frame.payload);
+    Ok(buf)
+}
+
+pub fn write_frame<W: Write>(writer: &mut W, frame: &Frame) -> io::Result<()> {
+    let buf = encode_frame(frame)?;
+    writer.write_all(&buf)?;
+    writer.flush()
+}
+
+// One frame in a UDP datagram, see the header comment
+pub fn encode_datagram(frame: &Frame) -> io::Result<Vec<u8>> {
+    let datagram = encode_frame(frame)?;
+    if datagram.len() > MAX_DATAGRAM {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "frame too large for a UDP datagram"));
+    }
+    Ok(datagram)
+}
+
+pub fn decode_datagram(datagram: &[u8]) -> io::Result<Frame> {
+    let mut rest = datagram;
+    match read_frame(&mut rest)? {
+        Some(frame) if rest.is_empty() => Ok(frame),
+        Some(_) => Err(io::Error::new(io::ErrorKind::InvalidData, "datagram length does not match its frame")),
+        None => Err(io::Error::new(io::ErrorKind::InvalidData, "empty datagram")),
+    }
+}
+
+fn frame_id(header: &[u8; HEADER_LEN]) -> u32 {
+    u32::from_be_bytes([header[5], header[6], header[7], header[8]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Hands out at most one byte per read, like a connection that splits every segment
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = buf.len().min(self.0.len()).min(1);
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn payload_over_512_bytes_round_trips() {
+        let frame = Frame::new(OP_UPPER, vec![b'a'; 2000]).with_id(77);
+        let bytes = encode_frame(&frame).unwrap();
+        assert_eq!(bytes.len(), HEADER_LEN + 2000);
+        assert_eq!(read_frame(&mut bytes.as_slice()).unwrap(), Some(frame.clone()));
+        assert_eq!(read_frame(&mut Trickle(&bytes)).unwrap(), Some(frame));
+    }
+
+    #[test]
+    fn two_frames_in_one_buffer() {
+        let first = Frame::new(OP_UPPER, "abc").with_id(1);
+        let second = Frame::new(OP_COUNT, "").with_id(2);
+        let mut bytes = encode_frame(&first).unwrap();
+        bytes.extend(encode_frame(&second).unwrap());
+
+        let mut reader = bytes.as_slice();
+        assert_eq!(read_frame(&mut reader).unwrap(), Some(first));
+        assert_eq!(read_frame(&mut reader).unwrap(), Some(second));
+        assert_eq!(read_frame(&mut reader).unwrap(), None);
+    }
+
+    #[test]
+    fn clean_eof_between_frames() {
+        assert_eq!(read_frame(&mut [].as_slice()).unwrap(), None);
+    }
+
+    #[test]
+    fn eof_inside_a_frame() {
+        let bytes = encode_frame(&Frame::new(OP_UPPER, "hello").with_id(3)).unwrap();
+        // Cut in the header and in the payload
+        for cut in [1, HEADER_LEN - 1, HEADER_LEN + 2] {
+            let err = read_frame(&mut &bytes[..cut]).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof, "cut at {}", cut);
+        }
+    }
+
+    #[test]
+    fn oversized_length_is_rejected() {
+        let mut header = ((MAX_PAYLOAD + 1) as u32).to_be_bytes().to_vec();
+        header.extend([OP_UPPER, 0, 0, 0, 1]);
+        let err = read_frame(&mut header.as_slice()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let err = encode_frame(&Frame::new(OP_UPPER, vec![0; MAX_PAYLOAD + 1])).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn datagram_must_hold_exactly_one_frame() {
+        let frame = Frame::new(OP_UPPER, "x").with_id(9);
+        let datagram = encode_datagram(&frame).unwrap();
+        assert_eq!(decode_datagram(&datagram).unwrap(), frame);
+        assert!(decode_datagram(&datagram[..datagram.len() - 1]).is_err());
+        let mut doubled = datagram.clone();
+        doubled.extend(&datagram);
+        assert!(decode_datagram(&doubled).is_err());
+    }
+}
@@ -0,0 +1,150 @@
+// Reader thread of a TCP connection
+//
+// Once the menu starts this thread is the only one reading from the server. Replies
+// go to the main thread over a channel, stamped with their arrival time for the RTT;
+// the main thread matches them to its request by id. Pushes (see protocol) are
+// handled here as they arrive: notices show up even while the menu waits for input,
+// the resume token is kept, and busy, shutdown or a kick end the client. When the
+// connection drops the thread ends the client too, unless the user is leaving anyway;
+// with --reconnect it connects again instead, see reconnect. A kicked client never
+// reconnects: the operator wants it gone.
+
+use std::io::{self, ErrorKind};
+use std::process;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+
+use crate::protocol::{read_frame, Frame, OP_BUSY, OP_KICK, OP_NOTICE, OP_SESSION, OP_SHUTDOWN};
+use crate::reconnect::Reconnect;
+use crate::tls::ReadHalf;
+
+// State shared by the reader thread and the main thread
+#[derive(Default)]
+pub struct Status {
+    // The user chose to exit, a closing connection is expected
+    pub stopping: AtomicBool,
+    // The main thread is waiting for a reply
+    pub awaiting_reply: AtomicBool,
+    // The server announced its shutdown
+    pub closing: AtomicBool,
+    // Resume token of this session, pushed by the server right after connect
+    pub session_token: Mutex<Option<String>>,
+    // Connections made by --reconnect so far, bumped when the new one takes over
+    pub generation: AtomicU32,
+}
+
+pub struct Reply {
+    pub frame: Frame,
+    pub received_at: Instant,
+}
+
+// What the reader thread hands to the main thread
+pub enum Event {
+    Reply(Reply),
+    // --reconnect replaced the connection; replies to requests sent before are lost
+    Reconnected,
+}
+
+// Start the reader thread; replies come out of the returned channel
+pub fn spawn(reader: ReadHalf, status: Arc<Status>, reconnect: Option<Reconnect>) -> Receiver<Event> {
+    let (events, received) = mpsc::channel();
+    thread::spawn(move || run(reader, &status, events, reconnect));
+    received
+}
+
+fn run(mut reader: ReadHalf, status: &Status, events: Sender<Event>, reconnect: Option<Reconnect>) {
+    loop {
+        if !serve(&mut reader, status, &events, reconnect.is_some()) {
+            return;
+        }
+        // The connection ended. Expected after the user chose to exit or the server
+        // announced its shutdown; anything else ends the client with a way back into
+        // the session, or starts a reconnect.
+        if status.stopping.load(Ordering::SeqCst) || status.closing.load(Ordering::SeqCst) {
+            return;
+        }
+        let Some(reconnect) = &reconnect else {
+            println!("\nServer disconnected. Terminating.");
+            print_resume_hint(&status.session_token);
+            process::exit(0);
+        };
+        let Some(new_reader) = reconnect.run(status) else {
+            print_resume_hint(&status.session_token);
+            process::exit(0);
+        };
+        reader = new_reader;
+        if events.send(Event::Reconnected).is_err() {
+            return;
+        }
+    }
+}
+
+// Read frames until the connection ends; false when the main thread is gone
+fn serve(reader: &mut ReadHalf, status: &Status, events: &Sender<Event>, reconnecting: bool) -> bool {
+    loop {
+        let frame = match read_frame(reader) {
+            Ok(Some(frame)) => frame,
+            Ok(None) => return true,
+            // Whatever broke the connection, --reconnect is there to get it back
+            Err(e) if reconnecting || is_reset(&e) => return true,
+            Err(e) => {
+                if status.stopping.load(Ordering::SeqCst) || status.closing.load(Ordering::SeqCst) {
+                    return true;
+                }
+                println!("\nUnexpected connection error: {}", e);
+                process::exit(1);
+            }
+        };
+        if !frame.is_push() {
+            let reply = Reply { frame, received_at: Instant::now() };
+            if events.send(Event::Reply(reply)).is_err() {
+                return false;
+            }
+            continue;
+        }
+        match frame.opcode {
+            OP_NOTICE => println!("\nNotice from server: {}", frame.text()),
+            OP_SESSION => *status.session_token.lock().unwrap() = Some(frame.text()),
+            OP_KICK => {
+                // The server does not keep a kicked session, so no resume hint either
+                println!("\nNotice from server: {}", frame.text());
+                process::exit(0);
+            }
+            OP_BUSY => {
+                // Server refused us because it is saturated, it closes right after
+                println!("\nServer is busy. Try again later.");
+                process::exit(1);
+            }
+            // With --reconnect, wait for the server to come back instead
+            OP_SHUTDOWN if reconnecting => println!("\nServer is shutting down."),
+            OP_SHUTDOWN => {
+                // Set before looking at awaiting_reply; the main thread clears that
+                // before looking at closing, so one of us says goodbye
+                status.closing.store(true, Ordering::SeqCst);
+                if !status.awaiting_reply.load(Ordering::SeqCst) {
+                    println!("\nServer is shutting down. Bye bye~");
+                    process::exit(0);
+                }
+                // The main thread still gets its reply and ends after it
+                println!("\nServer is shutting down.");
+            }
+            // A push this client does not know yet
+            _ => {}
+        }
+    }
+}
+
+// After an unexpected disconnect, tell the user how to get the session back
+pub fn print_resume_hint(session_token: &Mutex<Option<String>>) {
+    if let Some(token) = session_token.lock().unwrap().as_deref() {
+        println!("To pick up where you left off, restart the client with --resume {}", token);
+    }
+}
+
+// Connection reset by the server, e.g. when it was killed
+fn is_reset(e: &io::Error) -> bool {
+    e.kind() == ErrorKind::ConnectionReset || e.kind() == ErrorKind::ConnectionAborted
+}
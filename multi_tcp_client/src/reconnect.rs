@@ -0,0 +1,94 @@
+// Automatic reconnect (--reconnect)
+//
+// Without it the client ends when the connection drops. With it the reader thread
+// connects again, waiting longer after each failed attempt: the delay starts at
+// INITIAL_DELAY and doubles up to MAX_DELAY, and every wait is picked at random
+// between half the delay and all of it, so clients dropped together do not all come
+// back at the same moment. A new connection goes through TLS, takes the session back
+// with its resume token and repeats the version handshake; then the reader thread
+// swaps in the new write half and carries on. The menu and the RTT history live on
+// the main thread and are not touched.
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use multi_tcp_client::{Error, OptClient};
+
+use crate::config::Config;
+use crate::menu::report_handshake;
+use crate::reader::Status;
+use crate::tls::{ReadHalf, WriteHalf};
+
+const INITIAL_DELAY: Duration = Duration::from_millis(500);
+const MAX_DELAY: Duration = Duration::from_secs(30);
+// A server that accepts but does not answer the handshake counts as a failed attempt
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub struct Reconnect {
+    server_addr: String,
+    config: Config,
+    // The main thread writes through this; a new connection replaces what is inside
+    writer: Arc<Mutex<WriteHalf>>,
+}
+
+impl Reconnect {
+    pub fn new(server_addr: &str, config: &Config, writer: Arc<Mutex<WriteHalf>>) -> Reconnect {
+        Reconnect { server_addr: server_addr.to_string(), config: config.clone(), writer }
+    }
+
+    // Try to get the connection back; None once --reconnect-attempts attempts failed
+    pub fn run(&self, status: &Status) -> Option<ReadHalf> {
+        println!("\nConnection to the server lost. Reconnecting...");
+        let attempts = self.config.reconnect_attempts;
+        let mut delay = INITIAL_DELAY;
+        for attempt in 1..=attempts {
+            let wait = jitter(delay);
+            println!("Reconnect attempt {}/{} in {:.1} s", attempt, attempts, wait.as_secs_f64());
+            thread::sleep(wait);
+            match self.connect(status) {
+                Ok(reader) => {
+                    println!("Reconnected to the server.");
+                    return Some(reader);
+                }
+                Err(e) => println!("Reconnect failed: {}", e),
+            }
+            delay = (delay * 2).min(MAX_DELAY);
+        }
+        println!("Giving up after {} attempts.", attempts);
+        None
+    }
+
+    fn connect(&self, status: &Status) -> Result<ReadHalf, Error> {
+        let mut builder = OptClient::builder().config(self.config.clone()).reply_timeout(HANDSHAKE_TIMEOUT);
+        let token = status.session_token.lock().unwrap().clone();
+        if let Some(token) = token {
+            builder = builder.resume(token);
+        }
+        let mut client = builder.connect(self.server_addr.as_str())?;
+        report_handshake(&mut client);
+        if let Some(token) = client.session_token() {
+            *status.session_token.lock().unwrap() = Some(token.to_string());
+        }
+
+        // The reader thread waits for the server as long as the connection is up
+        client.set_reply_timeout(None)?;
+        let (read_half, write_half) = client.into_split()?;
+        let mut writer = self.writer.lock().unwrap();
+        *writer = write_half;
+        // Under the writer lock, so the main thread knows which connection it wrote to
+        status.generation.fetch_add(1, Ordering::SeqCst);
+        Ok(read_half)
+    }
+}
+
+// A random wait between half the delay and the full delay
+fn jitter(delay: Duration) -> Duration {
+    // RandomState is seeded randomly for every process, good enough for a backoff
+    let random = RandomState::new().build_hasher().finish();
+    let fraction = 0.5 + (random % 1000) as f64 / 2000.0;
+    delay.mul_f64(fraction)
+}
@@ -0,0 +1,180 @@
+// Scripted mode: run commands given on the command line instead of the menu
+//
+// `multi_tcp_client count` or `multi_tcp_client batch steps.txt` connects over TCP
+// (TLS with --tls), does the version handshake, sends one request per command and
+// prints each reply on stdout: the reply text, or with --json one object per
+// request with its id and RTT. Notices from the server go to stderr, so stdout only
+// carries results. A batch file holds one command per line; its requests are
+// pipelined, all sent before the replies are read, and the results are printed in
+// file order. A reply with an error does not stop it, a connection or protocol
+// error does. The exit code tells the outcomes apart, see the EXIT_* constants.
+
+use std::fs;
+use std::time::Duration;
+
+use multi_tcp_client::{Error, OptClient};
+use serde_json::json;
+
+use crate::config::Config;
+use crate::protocol::{Frame, OP_COUNT, OP_ERROR, OP_REPLY, OP_UPPER, OP_UPTIME, OP_WHO, OP_WHOAMI};
+
+pub const EXIT_OK: i32 = 0;
+// The server answered at least one request with an error
+pub const EXIT_SERVER_ERROR: i32 = 1;
+// Bad command or batch file; the same code as bad flags
+pub const EXIT_USAGE: i32 = 2;
+// No connection, TLS failure, server busy or gone
+pub const EXIT_CONNECTION: i32 = 3;
+// Failed handshake, command the server does not offer, reply that makes no sense
+pub const EXIT_PROTOCOL: i32 = 4;
+
+// One command: its name for the output and the request it sends
+struct Step {
+    name: &'static str,
+    request: Frame,
+}
+
+// Run config.command against the server and return the exit code
+pub fn run(server_addr: &str, config: &Config) -> i32 {
+    let steps = match steps(&config.command) {
+        Ok(steps) => steps,
+        Err(message) => {
+            report(config, None, &message);
+            return EXIT_USAGE;
+        }
+    };
+    let mut client = match OptClient::builder().config(config.clone()).connect(server_addr) {
+        Ok(client) => client,
+        Err(e) => {
+            report(config, None, &format!("{}: {}", server_addr, e));
+            return exit_code(&e);
+        }
+    };
+
+    let code = run_steps(config, &mut client, steps);
+    // Leave like OPT5 does, so the server does not keep the session for resumption
+    let _ = client.close();
+    code
+}
+
+// Send every step at once and print the replies in step order
+fn run_steps(config: &Config, client: &mut OptClient, steps: Vec<Step>) -> i32 {
+    let names: Vec<(&str, u8)> = steps.iter().map(|step| (step.name, step.request.opcode)).collect();
+    let mut replies = match client.pipeline(steps.into_iter().map(|step| step.request).collect()) {
+        Ok(replies) => replies,
+        Err(Error::Unsupported(opcode)) => {
+            let name = names.iter().find(|&&(_, known)| known == opcode).map_or("?", |&(name, _)| name);
+            report(config, Some(name), &format!("the server does not support '{}'", name));
+            return EXIT_PROTOCOL;
+        }
+        Err(e) => {
+            report(config, None, &e.to_string());
+            return exit_code(&e);
+        }
+    };
+
+    let mut code = EXIT_OK;
+    let mut shutdown_shown = false;
+    for &(name, _) in &names {
+        let Some(result) = replies.next() else { break };
+        for notice in replies.take_notices() {
+            eprintln!("Notice from server: {}", notice);
+        }
+        if replies.is_shutting_down() && !shutdown_shown {
+            eprintln!("Server is shutting down.");
+            shutdown_shown = true;
+        }
+        let response = match result {
+            Ok(response) => response,
+            Err(e) => {
+                report(config, Some(name), &e.to_string());
+                return exit_code(&e);
+            }
+        };
+        let frame = &response.frame;
+        match frame.opcode {
+            OP_REPLY => print_result(config, name, frame.id, Ok(&frame.text()), response.rtt),
+            OP_ERROR => {
+                print_result(config, name, frame.id, Err(&frame.text()), response.rtt);
+                code = EXIT_SERVER_ERROR;
+            }
+            other => {
+                report(config, Some(name), &format!("unexpected reply (opcode 0x{:02x})", other));
+                return EXIT_PROTOCOL;
+            }
+        }
+    }
+    code
+}
+
+// The exit code that goes with an error that ended the run
+pub fn exit_code(e: &Error) -> i32 {
+    match e {
+        Error::Io(_) | Error::Busy | Error::Closed => EXIT_CONNECTION,
+        Error::Server(_) => EXIT_SERVER_ERROR,
+        Error::Handshake(_) | Error::Unsupported(_) | Error::Protocol(_) => EXIT_PROTOCOL,
+    }
+}
+
+// The commands to run: the one on the command line, or the lines of a batch file
+fn steps(command: &[String]) -> Result<Vec<Step>, String> {
+    let words: Vec<&str> = command.iter().map(String::as_str).collect();
+    let ["batch", rest @ ..] = words.as_slice() else {
+        return Ok(vec![step(&words)?]);
+    };
+    let [path] = rest else {
+        return Err("usage: batch <file>".to_string());
+    };
+    let text = fs::read_to_string(path).map_err(|e| format!("cannot read {}: {}", path, e))?;
+    let mut steps = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if words[0] == "batch" {
+            return Err(format!("{} line {}: batch files cannot run other batch files", path, number + 1));
+        }
+        steps.push(step(&words).map_err(|e| format!("{} line {}: {}", path, number + 1, e))?);
+    }
+    Ok(steps)
+}
+
+fn step(words: &[&str]) -> Result<Step, String> {
+    let (name, request) = match words {
+        ["upper", text @ ..] if !text.is_empty() => ("upper", Frame::new(OP_UPPER, text.join(" "))),
+        ["upper"] => return Err("usage: upper <text>".to_string()),
+        ["uptime"] => ("uptime", Frame::empty(OP_UPTIME)),
+        ["whoami"] => ("whoami", Frame::empty(OP_WHOAMI)),
+        ["count"] => ("count", Frame::empty(OP_COUNT)),
+        ["who"] => ("who", Frame::empty(OP_WHO)),
+        [name @ ("uptime" | "whoami" | "count" | "who"), ..] => return Err(format!("'{}' takes no arguments", name)),
+        [other, ..] => return Err(format!("unknown command '{}'", other)),
+        [] => return Err("missing command".to_string()),
+    };
+    Ok(Step { name, request })
+}
+
+// One line per request: the reply text, or a JSON object with the request id and RTT
+fn print_result(config: &Config, name: &str, id: u32, reply: Result<&str, &str>, rtt: Duration) {
+    let rtt_ms = rtt.as_micros() as f64 / 1000.0;
+    match (config.json, reply) {
+        (true, Ok(text)) => println!("{}", json!({"command": name, "id": id, "ok": true, "reply": text, "rtt_ms": rtt_ms})),
+        (true, Err(text)) => println!("{}", json!({"command": name, "id": id, "ok": false, "error": text, "rtt_ms": rtt_ms})),
+        (false, Ok(text)) => println!("{}", text),
+        (false, Err(text)) => eprintln!("{}: error from server: {}", name, text),
+    }
+}
+
+// A failure that ends the run; still a JSON object on stdout with --json
+fn report(config: &Config, name: Option<&str>, message: &str) {
+    if config.json {
+        println!("{}", json!({"command": name, "ok": false, "error": message}));
+    } else {
+        match name {
+            Some(name) => eprintln!("{}: {}", name, message),
+            None => eprintln!("{}", message),
+        }
+    }
+}
@@ -0,0 +1,118 @@
+// RTT history of the session
+//
+// Every answered request adds a sample. Menu entry 7 sums them up: min/avg/max,
+// jitter and a histogram; entry 8 writes the samples to a CSV file, so runs over
+// different networks can be compared later.
+//
+// Jitter is the mean difference between the RTTs of consecutive requests, the
+// unsmoothed form of the interarrival jitter of RFC 3550.
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::protocol::{OP_COUNT, OP_UPPER, OP_UPTIME, OP_WHO, OP_WHOAMI};
+
+// Upper bounds of the histogram buckets in ms; the last bucket takes the rest
+const BUCKETS_MS: [f64; 12] = [0.1, 0.2, 0.5, 1.0, 2.0, 5.0, 10.0, 20.0, 50.0, 100.0, 200.0, 500.0];
+
+// Width of the longest histogram bar
+const BAR_WIDTH: usize = 40;
+
+struct Sample {
+    // When the reply came in
+    at: SystemTime,
+    opcode: u8,
+    rtt: Duration,
+    // UDP only: transmissions before the one that was answered
+    retransmissions: u32,
+}
+
+#[derive(Default)]
+pub struct RttHistory {
+    samples: Vec<Sample>,
+}
+
+impl RttHistory {
+    pub fn record(&mut self, opcode: u8, rtt: Duration, retransmissions: u32) {
+        self.samples.push(Sample { at: SystemTime::now(), opcode, rtt, retransmissions });
+    }
+
+    pub fn print(&self) {
+        if self.samples.is_empty() {
+            println!("No RTT measured yet, send a request first.");
+            return;
+        }
+        let rtts: Vec<f64> = self.samples.iter().map(|sample| ms(sample.rtt)).collect();
+        let min = rtts.iter().copied().fold(f64::INFINITY, f64::min);
+        let max = rtts.iter().copied().fold(0.0, f64::max);
+        let avg = rtts.iter().sum::<f64>() / rtts.len() as f64;
+        println!("RTT over {} request{}:", rtts.len(), if rtts.len() == 1 { "" } else { "s" });
+        println!("  min {:.3} ms, avg {:.3} ms, max {:.3} ms", min, avg, max);
+        if rtts.len() > 1 {
+            let jitter = rtts.windows(2).map(|pair| (pair[1] - pair[0]).abs()).sum::<f64>() / (rtts.len() - 1) as f64;
+            println!("  jitter {:.3} ms", jitter);
+        }
+
+        let mut counts = [0usize; BUCKETS_MS.len() + 1];
+        for rtt in &rtts {
+            counts[BUCKETS_MS.iter().position(|bound| rtt < bound).unwrap_or(BUCKETS_MS.len())] += 1;
+        }
+        // Only the range that has samples, empty buckets in between stay
+        let first = counts.iter().position(|&count| count > 0).unwrap_or(0);
+        let last = counts.iter().rposition(|&count| count > 0).unwrap_or(0);
+        let most = counts.iter().copied().max().unwrap_or(1);
+        for (bucket, &count) in counts.iter().enumerate().take(last + 1).skip(first) {
+            let label = match bucket {
+                0 => format!("< {} ms", BUCKETS_MS[0]),
+                b if b == BUCKETS_MS.len() => format!(">= {} ms", BUCKETS_MS[b - 1]),
+                b => format!("{}-{} ms", BUCKETS_MS[b - 1], BUCKETS_MS[b]),
+            };
+            // Any sample gets at least one mark
+            let bar = (count * BAR_WIDTH).div_ceil(most);
+            println!("  {:>12} | {:<width$} {}", label, "#".repeat(bar), count, width = BAR_WIDTH);
+        }
+    }
+
+    pub fn export(&self, path: &Path) {
+        match self.write_csv(path) {
+            Ok(()) => println!("Wrote {} RTT sample(s) to {}", self.samples.len(), path.display()),
+            Err(e) => println!("Cannot write {}: {}", path.display(), e),
+        }
+    }
+
+    // One line per request, oldest first
+    fn write_csv(&self, path: &Path) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        writeln!(out, "seq,unix_time_ms,command,rtt_ms,retransmissions")?;
+        for (seq, sample) in self.samples.iter().enumerate() {
+            let at = sample.at.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+            writeln!(
+                out,
+                "{},{},{},{:.3},{}",
+                seq + 1,
+                at,
+                command_name(sample.opcode),
+                ms(sample.rtt),
+                sample.retransmissions
+            )?;
+        }
+        out.flush()
+    }
+}
+
+fn ms(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+fn command_name(opcode: u8) -> &'static str {
+    match opcode {
+        OP_UPPER => "OPT1",
+        OP_UPTIME => "OPT2",
+        OP_WHOAMI => "OPT3",
+        OP_COUNT => "OPT4",
+        OP_WHO => "OPT6",
+        _ => "other",
+    }
+}
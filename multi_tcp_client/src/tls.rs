@@ -0,0 +1,273 @@
+// TLS for the TCP connection (rustls)
+//
+// With --tls the client runs the TLS handshake right after connecting and checks the
+// server certificate against the CAs in --tls-ca, for the name in --tls-server-name.
+// --tls-cert and --tls-key present a client certificate to a server that asks for
+// one. The frames travel inside the TLS stream unchanged.
+//
+// Before the menu starts the connection is used from one thread. split() then hands
+// the read side to the reader thread and the write side to the main thread; over TLS
+// both halves share the rustls state, and the reader waits on the socket without
+// holding its lock, so writing never waits for the server to send something.
+
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+
+use crate::config::Config;
+
+// The connection to the server, plain or TLS
+pub enum Connection {
+    Plain(TcpStream),
+    Tls(Box<StreamOwned<ClientConnection, TcpStream>>),
+}
+
+impl Connection {
+    // Wrap a fresh connection, running the TLS handshake if --tls is set
+    pub fn open(socket: TcpStream, config: &Config) -> io::Result<Connection> {
+        if !config.tls {
+            return Ok(Connection::Plain(socket));
+        }
+        let name = ServerName::try_from(config.tls_server_name.clone())
+            .map_err(|_| invalid(format!("invalid server name '{}'", config.tls_server_name)))?;
+        let mut conn = ClientConnection::new(Arc::new(client_config(config)?), name).map_err(io::Error::other)?;
+        let mut socket = socket;
+        while conn.is_handshaking() {
+            conn.complete_io(&mut socket)?;
+        }
+        Ok(Connection::Tls(Box::new(StreamOwned::new(conn, socket))))
+    }
+
+    // The negotiated TLS version, None for a plain connection
+    pub fn tls_version(&self) -> Option<String> {
+        match self {
+            Connection::Plain(_) => None,
+            Connection::Tls(stream) => stream.conn.protocol_version().map(|version| format!("{:?}", version)),
+        }
+    }
+
+    // The TCP socket underneath, for its addresses
+    pub fn socket(&self) -> &TcpStream {
+        match self {
+            Connection::Plain(stream) => stream,
+            Connection::Tls(stream) => &stream.sock,
+        }
+    }
+
+    // Close both directions; over TLS the server is told with close_notify first.
+    // A server that already closed its end (e.g. after OPT5) is not an error.
+    pub fn shutdown(&mut self) -> io::Result<()> {
+        let result = match self {
+            Connection::Plain(stream) => stream.shutdown(Shutdown::Both),
+            Connection::Tls(stream) => {
+                stream.conn.send_close_notify();
+                let mut flushed = Ok(());
+                while stream.conn.wants_write() && flushed.is_ok() {
+                    flushed = stream.conn.write_tls(&mut stream.sock).map(|_| ());
+                }
+                flushed.and(stream.sock.shutdown(Shutdown::Both))
+            }
+        };
+        match result {
+            Err(e) if matches!(e.kind(), ErrorKind::NotConnected | ErrorKind::BrokenPipe | ErrorKind::ConnectionReset) => Ok(()),
+            result => result,
+        }
+    }
+
+    // Separate halves for the reader thread and the main thread
+    pub fn split(self) -> io::Result<(ReadHalf, WriteHalf)> {
+        match self {
+            Connection::Plain(stream) => Ok((ReadHalf::Plain(stream.try_clone()?), WriteHalf::Plain(stream))),
+            Connection::Tls(stream) => {
+                let StreamOwned { conn, sock } = *stream;
+                let reader = sock.try_clone()?;
+                let shared = Arc::new(TlsShared { conn: Mutex::new(conn), socket: sock });
+                let read_half = TlsReader { shared: Arc::clone(&shared), socket: reader, raw: vec![0; 16 * 1024], start: 0, end: 0 };
+                Ok((ReadHalf::Tls(read_half), WriteHalf::Tls(shared)))
+            }
+        }
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Connection::Plain(stream) => stream.read(buf),
+            // A server that goes away without close_notify (killed, or closed by the
+            // kernel) counts as a closed connection, as it does without TLS;
+            // read_frame still reports a frame cut short
+            Connection::Tls(stream) => match stream.read(buf) {
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(0),
+                other => other,
+            },
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Connection::Plain(stream) => stream.write(buf),
+            Connection::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Connection::Plain(stream) => stream.flush(),
+            Connection::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+// rustls state shared by the two halves of a TLS connection
+pub struct TlsShared {
+    conn: Mutex<ClientConnection>,
+    socket: TcpStream,
+}
+
+// Write out whatever rustls has queued: records, alerts, close_notify
+fn flush_tls(conn: &mut MutexGuard<'_, ClientConnection>, mut socket: &TcpStream) -> io::Result<()> {
+    while conn.wants_write() {
+        conn.write_tls(&mut socket)?;
+    }
+    Ok(())
+}
+
+pub enum ReadHalf {
+    Plain(TcpStream),
+    Tls(TlsReader),
+}
+
+pub struct TlsReader {
+    shared: Arc<TlsShared>,
+    socket: TcpStream,
+    // Received bytes rustls has not taken yet
+    raw: Vec<u8>,
+    start: usize,
+    end: usize,
+}
+
+impl TlsReader {
+    // Wait until plaintext is available; false once the server closed the connection
+    fn fill(&mut self) -> io::Result<bool> {
+        loop {
+            let mut conn = self.shared.conn.lock().unwrap();
+            let state = conn.process_new_packets();
+            // Alerts and key updates have to go out even if processing failed
+            let flushed = flush_tls(&mut conn, &self.socket);
+            let state = state.map_err(|e| invalid(e.to_string()))?;
+            flushed?;
+            if state.plaintext_bytes_to_read() > 0 {
+                return Ok(true);
+            }
+            if state.peer_has_closed() {
+                return Ok(false);
+            }
+            if self.start < self.end {
+                let mut raw = &self.raw[self.start..self.end];
+                self.start += conn.read_tls(&mut raw)?;
+                continue;
+            }
+            drop(conn);
+
+            // Without close_notify this is a server that went away, see Connection
+            let n = (&self.socket).read(&mut self.raw)?;
+            if n == 0 {
+                return Ok(false);
+            }
+            (self.start, self.end) = (0, n);
+        }
+    }
+}
+
+impl Read for ReadHalf {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            ReadHalf::Plain(stream) => stream.read(buf),
+            ReadHalf::Tls(reader) => {
+                if buf.is_empty() || !reader.fill()? {
+                    return Ok(0);
+                }
+                reader.shared.conn.lock().unwrap().reader().read(buf)
+            }
+        }
+    }
+}
+
+pub enum WriteHalf {
+    Plain(TcpStream),
+    Tls(Arc<TlsShared>),
+}
+
+impl WriteHalf {
+    // Close both directions; over TLS the server is told with close_notify first.
+    // The reader thread then sees the connection end.
+    pub fn shutdown(&self) -> io::Result<()> {
+        match self {
+            WriteHalf::Plain(stream) => stream.shutdown(Shutdown::Both),
+            WriteHalf::Tls(shared) => {
+                let mut conn = shared.conn.lock().unwrap();
+                conn.send_close_notify();
+                let _ = flush_tls(&mut conn, &shared.socket);
+                shared.socket.shutdown(Shutdown::Both)
+            }
+        }
+    }
+}
+
+impl Write for WriteHalf {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            WriteHalf::Plain(stream) => stream.write(buf),
+            WriteHalf::Tls(shared) => {
+                let mut conn = shared.conn.lock().unwrap();
+                let n = conn.writer().write(buf)?;
+                flush_tls(&mut conn, &shared.socket)?;
+                Ok(n)
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            WriteHalf::Plain(stream) => stream.flush(),
+            WriteHalf::Tls(_) => Ok(()),
+        }
+    }
+}
+
+// Trusted CAs and the optional client certificate
+fn client_config(config: &Config) -> io::Result<ClientConfig> {
+    let mut roots = RootCertStore::empty();
+    if let Some(ca) = &config.tls_ca {
+        for cert in CertificateDer::pem_file_iter(ca).map_err(|e| pem_error(ca, e))? {
+            roots.add(cert.map_err(|e| pem_error(ca, e))?).map_err(|e| invalid(format!("{}: {}", ca.display(), e)))?;
+        }
+    }
+    let builder = ClientConfig::builder().with_root_certificates(roots);
+    match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => {
+            let certs = CertificateDer::pem_file_iter(cert)
+                .map_err(|e| pem_error(cert, e))?
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| pem_error(cert, e))?;
+            let key_der = PrivateKeyDer::from_pem_file(key).map_err(|e| pem_error(key, e))?;
+            builder.with_client_auth_cert(certs, key_der).map_err(|e| invalid(format!("client certificate: {}", e)))
+        }
+        _ => Ok(builder.with_no_client_auth()),
+    }
+}
+
+fn pem_error(path: &Path, e: impl std::fmt::Display) -> io::Error {
+    invalid(format!("{}: {}", path.display(), e))
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message)
+}
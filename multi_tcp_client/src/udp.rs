@@ -0,0 +1,227 @@
+// UDP mode of the client
+//
+// Every request goes out as one datagram with a fresh request id. If no reply with
+// that id arrives within the timeout the datagram is sent again, with the timeout
+// doubled each time, up to --retries retransmissions. Replies with another id are
+// late answers to earlier requests and are ignored. RTT is measured from the
+// transmission that was answered. A server with no room for a new session answers
+// with OP_BUSY instead, which is reported like a busy TCP server.
+
+use std::io::{self, ErrorKind};
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::process;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use multi_tcp_client::Error;
+
+use crate::config::Config;
+use crate::menu::{self, Choice};
+use crate::protocol::{
+    check_hello, decode_datagram, encode_datagram, hello_request, Frame, MAX_DATAGRAM, OP_BUSY, OP_KICK, OP_NOTICE, OP_QUIT,
+    OP_SHUTDOWN,
+};
+use crate::stats::RttHistory;
+
+struct Exchange {
+    reply: Frame,
+    rtt: Duration,
+    retransmissions: u32,
+}
+
+pub fn run(server_addr: &str, config: &Config) -> io::Result<()> {
+    let server: SocketAddr = server_addr
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(ErrorKind::NotFound, format!("cannot resolve {}", server_addr)))?;
+    let local: SocketAddr = if server.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }.parse().unwrap();
+    let socket = Arc::new(UdpSocket::bind(local)?);
+    // Only accept datagrams from the server
+    socket.connect(server)?;
+
+    println!(
+        "Using UDP to server at {} from client at {}",
+        server,
+        socket.local_addr().unwrap()
+    );
+
+    // Ctrl+C: there is no connection to close, but end the session on the server
+    {
+        let socket = Arc::clone(&socket);
+        ctrlc::set_handler(move || {
+            if let Ok(datagram) = encode_datagram(&Frame::empty(OP_QUIT)) {
+                let _ = socket.send(&datagram);
+            }
+            println!("\nBye bye~");
+            process::exit(0);
+        }).expect("Error setting Ctrl-C handler");
+    }
+
+    // Version handshake first. Ids keep counting from it: the server would take a
+    // menu request with the same id for a retransmission of the handshake.
+    let mut request_id: u32 = 1;
+    let server = match request_reply(&socket, &hello_request().with_id(request_id), config) {
+        Ok(exchange) => check_hello(&exchange.reply).unwrap_or_else(|e| {
+            println!("Cannot talk to this server: {}", e);
+            process::exit(1);
+        }),
+        Err(e) => {
+            report_error(e, config)?;
+            process::exit(1);
+        }
+    };
+
+    let mut history = RttHistory::default();
+    loop {
+        menu::print_menu(&server);
+        let request = match menu::read_choice(&server)? {
+            Choice::Request(frame) => frame,
+            Choice::Exit => {
+                request_id = request_id.wrapping_add(1).max(1);
+                // Fire and forget, the server does not answer OPT5
+                let _ = socket.send(&encode_datagram(&Frame::empty(OP_QUIT).with_id(request_id))?);
+                println!("Bye bye~");
+                break;
+            }
+            Choice::ShowStats => {
+                history.print();
+                continue;
+            }
+            Choice::ExportStats(path) => {
+                history.export(&path);
+                continue;
+            }
+            Choice::Invalid => {
+                println!("Invalid option. Try again.");
+                continue;
+            }
+        };
+
+        // 0 is reserved for messages the server sends on its own
+        request_id = request_id.wrapping_add(1).max(1);
+        let opcode = request.opcode;
+        match request_reply(&socket, &request.with_id(request_id), config) {
+            Ok(exchange) => {
+                menu::print_reply(&exchange.reply);
+                history.record(opcode, exchange.rtt, exchange.retransmissions);
+                match exchange.retransmissions {
+                    0 => println!("RTT = {:.3} ms", exchange.rtt.as_secs_f64() * 1000.0),
+                    n => println!("RTT = {:.3} ms (after {} retransmission{})",
+                        exchange.rtt.as_secs_f64() * 1000.0, n, if n == 1 { "" } else { "s" }),
+                }
+            }
+            Err(e) => report_error(e, config)?,
+        }
+    }
+    Ok(())
+}
+
+// Explain a request that got no reply or was turned away; other errors are passed on
+fn report_error(e: Error, config: &Config) -> io::Result<()> {
+    match e {
+        Error::Busy => println!("Server is busy. Try again later."),
+        Error::Io(e) if e.kind() == ErrorKind::TimedOut => {
+            println!("No reply from server after {} attempts. Is the server running with --udp?", config.retries + 1);
+        }
+        Error::Io(e) if e.kind() == ErrorKind::ConnectionRefused => {
+            println!("Server is not listening on UDP (connection refused).")
+        }
+        Error::Io(e) => return Err(e),
+        e => return Err(io::Error::other(e)),
+    }
+    Ok(())
+}
+
+fn request_reply(socket: &UdpSocket, request: &Frame, config: &Config) -> Result<Exchange, Error> {
+    let datagram = encode_datagram(request)?;
+    let mut buf = vec![0u8; MAX_DATAGRAM];
+    let mut timeout = config.timeout;
+
+    for attempt in 0..=config.retries {
+        let sent_at = Instant::now();
+        socket.send(&datagram)?;
+
+        let deadline = sent_at + timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                break;
+            }
+            socket.set_read_timeout(Some(remaining))?;
+            let n = match socket.recv(&mut buf) {
+                Ok(n) => n,
+                Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => break,
+                Err(e) => return Err(e.into()),
+            };
+            match decode_datagram(&buf[..n]) {
+                // Sent in place of the reply, there is no session to retry on
+                Ok(reply) if reply.id == request.id && reply.opcode == OP_BUSY => return Err(Error::Busy),
+                Ok(reply) if reply.id == request.id => {
+                    return Ok(Exchange { reply, rtt: sent_at.elapsed(), retransmissions: attempt });
+                }
+                Ok(notice) if notice.is_push() && notice.opcode == OP_SHUTDOWN => {
+                    println!("Server is shutting down. Bye bye~");
+                    process::exit(0);
+                }
+                Ok(notice) if notice.is_push() && notice.opcode == OP_NOTICE => {
+                    println!("Notice from server: {}", notice.text());
+                }
+                Ok(notice) if notice.is_push() && notice.opcode == OP_KICK => {
+                    println!("Notice from server: {}", notice.text());
+                    process::exit(0);
+                }
+                // Late reply to an earlier transmission, or garbage
+                _ => continue,
+            }
+        }
+        if attempt < config.retries {
+            println!("No reply within {} ms, retransmitting ({}/{})", timeout.as_millis(), attempt + 1, config.retries);
+        }
+        timeout *= 2;
+    }
+    Err(io::Error::new(ErrorKind::TimedOut, "no reply from server").into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{OP_COUNT, OP_REPLY};
+
+    // A server socket and a client socket connected to it
+    fn pair() -> (UdpSocket, UdpSocket) {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.connect(server.local_addr().unwrap()).unwrap();
+        (server, client)
+    }
+
+    // Answer the next request with a frame of this opcode, tagged with its id
+    fn answer(server: UdpSocket, opcode: u8) -> std::thread::JoinHandle<()> {
+        std::thread::spawn(move || {
+            let mut buf = vec![0u8; MAX_DATAGRAM];
+            let (n, from) = server.recv_from(&mut buf).unwrap();
+            let request = decode_datagram(&buf[..n]).unwrap();
+            let reply = Frame::new(opcode, "server busy, try again later").with_id(request.id);
+            server.send_to(&encode_datagram(&reply).unwrap(), from).unwrap();
+        })
+    }
+
+    #[test]
+    fn busy_reply_is_reported_as_busy() {
+        let (server, client) = pair();
+        let answering = answer(server, OP_BUSY);
+        let result = request_reply(&client, &hello_request().with_id(1), &Config::default());
+        answering.join().unwrap();
+        assert!(matches!(result, Err(Error::Busy)), "{:?}", result.err());
+    }
+
+    #[test]
+    fn reply_with_the_request_id_is_returned() {
+        let (server, client) = pair();
+        let answering = answer(server, OP_REPLY);
+        let exchange = request_reply(&client, &Frame::empty(OP_COUNT).with_id(7), &Config::default()).unwrap();
+        answering.join().unwrap();
+        assert_eq!(exchange.reply.id, 7);
+        assert_eq!(exchange.retransmissions, 0);
+    }
+}
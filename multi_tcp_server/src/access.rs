@@ -0,0 +1,217 @@
+// IP allow/deny lists for incoming clients
+//
+// The list file has one rule per line, `allow <cidr>` or `deny <cidr>`; a bare
+// address counts as a single host (/32 or /128). Blank lines and `#` comments are
+// ignored:
+//
+//   # office network, except the printer
+//   allow 10.20.0.0/16
+//   deny  10.20.3.7
+//
+// A deny rule always wins. With no allow rules every other peer is let in; with at
+// least one, a peer has to match one of them. IPv4-mapped IPv6 peers are checked as
+// their IPv4 address. The list is consulted when a TCP connection is accepted or a
+// UDP client sends its first datagram, so a reload applies to new clients only.
+
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::Path;
+use std::str::FromStr;
+
+// An address block in CIDR notation, e.g. 192.168.0.0/24
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(self.prefix)).unwrap_or(0);
+                (u32::from(network) ^ u32::from(ip)) & mask == 0
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(self.prefix)).unwrap_or(0);
+                (u128::from(network) ^ u128::from(ip)) & mask == 0
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, prefix) = match s.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (s, None),
+        };
+        let network = address
+            .parse::<IpAddr>()
+            .map_err(|_| format!("invalid address '{}'", address))?
+            .to_canonical();
+        let max = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix| *prefix <= max)
+                .ok_or_else(|| format!("invalid prefix length '{}' for {}", prefix, address))?,
+            None => max,
+        };
+        Ok(Cidr { network, prefix })
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct AccessList {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+}
+
+impl AccessList {
+    pub fn new(allow: Vec<Cidr>, deny: Vec<Cidr>) -> Self {
+        AccessList { allow, deny }
+    }
+
+    // Parse the rules of a list file, see the header comment for the format
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut list = AccessList::default();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let fail = |message: String| format!("line {}: {}", number + 1, message);
+            let mut words = line.split_whitespace();
+            let (Some(action), Some(cidr), None) = (words.next(), words.next(), words.next()) else {
+                return Err(fail(format!("expected 'allow <cidr>' or 'deny <cidr>', got '{}'", line)));
+            };
+            let cidr: Cidr = cidr.parse().map_err(fail)?;
+            match action {
+                "allow" => list.allow.push(cidr),
+                "deny" => list.deny.push(cidr),
+                other => return Err(fail(format!("unknown action '{}'", other))),
+            }
+        }
+        Ok(list)
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let text = fs::read_to_string(path)?;
+        AccessList::parse(&text)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))
+    }
+
+    // Whether a peer with this address may connect
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        if self.deny.iter().any(|cidr| cidr.contains(ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    pub fn allow_rules(&self) -> usize {
+        self.allow.len()
+    }
+
+    pub fn deny_rules(&self) -> usize {
+        self.deny.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap()
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn prefix_zero_matches_the_whole_family() {
+        assert!(cidr("0.0.0.0/0").contains(ip("203.0.113.9")));
+        assert!(cidr("0.0.0.0/0").contains(ip("255.255.255.255")));
+        assert!(!cidr("0.0.0.0/0").contains(ip("2001:db8::1")));
+        assert!(cidr("::/0").contains(ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn full_length_prefix_is_one_host() {
+        assert!(cidr("10.0.0.1/32").contains(ip("10.0.0.1")));
+        assert!(!cidr("10.0.0.1/32").contains(ip("10.0.0.2")));
+        assert!(cidr("2001:db8::1/128").contains(ip("2001:db8::1")));
+        assert!(!cidr("2001:db8::1/128").contains(ip("2001:db8::2")));
+        // A bare address means the same
+        assert_eq!(cidr("10.0.0.1"), cidr("10.0.0.1/32"));
+        assert_eq!(cidr("2001:db8::1"), cidr("2001:db8::1/128"));
+    }
+
+    #[test]
+    fn partial_prefix() {
+        assert!(cidr("192.168.4.0/22").contains(ip("192.168.7.255")));
+        assert!(!cidr("192.168.4.0/22").contains(ip("192.168.8.0")));
+        assert!(cidr("2001:db8::/32").contains(ip("2001:db8:ffff::1")));
+        assert!(!cidr("2001:db8::/32").contains(ip("2001:db9::1")));
+    }
+
+    #[test]
+    fn ipv4_mapped_peers_count_as_ipv4() {
+        assert!(cidr("10.20.0.0/16").contains(ip("::ffff:10.20.3.7")));
+        assert!(!cidr("10.20.0.0/16").contains(ip("::ffff:10.21.0.1")));
+        // A mapped address in the list is an IPv4 rule too
+        assert!(cidr("::ffff:10.20.3.7").contains(ip("10.20.3.7")));
+    }
+
+    #[test]
+    fn invalid_cidrs() {
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("2001:db8::/129".parse::<Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/x".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn parse_skips_comments_and_blank_lines() {
+        let list = AccessList::parse("# office\n\nallow 10.20.0.0/16  # lan\n  deny 10.20.3.7\n").unwrap();
+        assert_eq!((list.allow_rules(), list.deny_rules()), (1, 1));
+    }
+
+    #[test]
+    fn parse_reports_the_bad_line() {
+        let err = AccessList::parse("allow 10.0.0.0/8\npermit 10.0.0.1\n").unwrap_err();
+        assert!(err.starts_with("line 2: unknown action 'permit'"), "{}", err);
+        let err = AccessList::parse("\nallow\n").unwrap_err();
+        assert!(err.starts_with("line 2: expected"), "{}", err);
+        let err = AccessList::parse("deny 10.0.0.1 10.0.0.2\n").unwrap_err();
+        assert!(err.starts_with("line 1: expected"), "{}", err);
+        let err = AccessList::parse("deny 300.0.0.1\n").unwrap_err();
+        assert!(err.starts_with("line 1: invalid address"), "{}", err);
+    }
+
+    #[test]
+    fn deny_always_wins() {
+        let list = AccessList::parse("deny 10.20.3.7\nallow 10.20.0.0/16\nallow 10.20.3.7\n").unwrap();
+        assert!(!list.permits(ip("10.20.3.7")));
+        assert!(!list.permits(ip("::ffff:10.20.3.7")));
+        assert!(list.permits(ip("10.20.3.8")));
+        // With allow rules, everything else stays out
+        assert!(!list.permits(ip("192.0.2.1")));
+    }
+
+    #[test]
+    fn without_allow_rules_everyone_else_is_let_in() {
+        let list = AccessList::parse("deny 192.0.2.0/24\n").unwrap();
+        assert!(!list.permits(ip("192.0.2.1")));
+        assert!(list.permits(ip("198.51.100.1")));
+        assert!(AccessList::default().permits(ip("2001:db8::1")));
+    }
+}
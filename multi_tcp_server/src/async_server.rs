@@ -0,0 +1,247 @@
+// Tokio-based TCP server, used with --async (cargo feature "async")
+//
+// Same commands, logging, statistics, rate limiting and shutdown sequence as the
+// threaded server; only the way connections are driven differs. Each connection is
+// a reader task, which parses frames and hands each request to serve_request on the
+// blocking pool, plus a writer task that owns the write half and works through the
+// client's outgoing queue. Up to MAX_IN_FLIGHT requests of one connection run at
+// the same time, so replies can overtake each other; clients match them by id.
+// Replies, console notices and the shutdown notice are queued from sync code, so
+// nothing ever blocks on a slow client. An idle connection costs two parked tasks
+// instead of an OS thread, which is what lets thousands of them stay open.
+// UDP, metrics, the console and the 10-second log still run on their own threads.
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use socket2::{SockRef, TcpKeepalive};
+use tokio::io::{AsyncWriteExt, BufReader};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+use tokio::sync::Semaphore;
+use tokio::task::{JoinError, JoinSet};
+
+use crate::clients::{ClientHandle, Outgoing};
+use crate::logger::{Fields, Level};
+use crate::protocol::{encode_frame, read_frame_async, Frame, OP_BUSY, OP_SHUTDOWN};
+use crate::server::{
+    admit, end_tcp_session, over_capacity, serve_request, shutdown, start_tcp_session, Flow, Shared, MAX_IN_FLIGHT,
+    RESUME_WAIT, WRITE_TIMEOUT,
+};
+
+// Accept until Ctrl+C, then drain like the threaded server does
+pub fn run(listener: std::net::TcpListener, shared: &Arc<Shared>) -> io::Result<()> {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .thread_name("async-worker")
+        .build()?;
+    // Connections with a reader task still running
+    let active = Arc::new(AtomicUsize::new(0));
+
+    listener.set_nonblocking(true)?;
+    runtime.block_on(async {
+        let listener = TcpListener::from_std(listener)?;
+        accept_loop(listener, Arc::clone(shared), Arc::clone(&active)).await;
+        Ok::<(), io::Error>(())
+    })?;
+
+    // The tasks keep running on the runtime's threads while we wait here
+    let grace_period = shared.config().grace_period;
+    shutdown(shared, || active.load(Ordering::SeqCst) == 0, grace_period);
+    runtime.shutdown_timeout(Duration::from_secs(1));
+    Ok(())
+}
+
+async fn accept_loop(listener: TcpListener, shared: Arc<Shared>, active: Arc<AtomicUsize>) {
+    loop {
+        let accepted = listener.accept().await;
+        // ServerHandle::begin_shutdown connects to us to get here
+        if !shared.running.load(Ordering::SeqCst) {
+            break;
+        }
+        match accepted {
+            Ok((_, addr)) if !admit(&shared, addr) => {}
+            Ok((stream, addr)) => {
+                let shared = Arc::clone(&shared);
+                let active = Arc::clone(&active);
+                active.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    handle_client(stream, addr, shared).await;
+                    active.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+            Err(e) => {
+                shared.metrics.record_accept_error();
+                shared.log.error(format_args!("Accept failed: {}", e));
+            }
+        }
+    }
+}
+
+// Connection lifetime of one TCP client, the async twin of server::handle_client
+async fn handle_client(mut stream: TcpStream, client_addr: SocketAddr, shared: Arc<Shared>) {
+    if !shared.running.load(Ordering::SeqCst) {
+        if let Ok(notice) = encode_frame(&Frame::push(OP_SHUTDOWN, "server shutting down")) {
+            let _ = stream.write_all(&notice).await;
+        }
+        return;
+    }
+    if let Some(why) = over_capacity(&shared) {
+        shared.log.log(Level::Warn, Fields::peer(client_addr),
+            format_args!("Server busy ({}). Rejected connection from {}", why, client_addr));
+        if let Ok(busy) = encode_frame(&Frame::push(OP_BUSY, "server busy, try again later")) {
+            let _ = stream.write_all(&busy).await;
+        }
+        return;
+    }
+    // Replies to pipelined requests go out one by one, Nagle's algorithm would delay them
+    if let Err(e) = stream.set_nodelay(true) {
+        shared.log.log(Level::Warn, Fields::peer(client_addr),
+            format_args!("Could not apply socket options for {}: {}", client_addr, e));
+    }
+    let (keepalive, keepalive_interval) = {
+        let config = shared.config();
+        (config.keepalive, config.keepalive_interval)
+    };
+    if let Some(idle) = keepalive {
+        let keepalive = TcpKeepalive::new()
+            .with_time(idle)
+            .with_interval(keepalive_interval);
+        if let Err(e) = SockRef::from(&stream).set_tcp_keepalive(&keepalive) {
+            shared.log.log(Level::Warn, Fields::peer(client_addr),
+                format_args!("Could not apply socket options for {}: {}", client_addr, e));
+        }
+    }
+
+    let first = match read_first_frame(&mut stream, &shared).await {
+        Ok(first) => first,
+        Err(_) => return,
+    };
+
+    let (reader, writer) = stream.into_split();
+    let (queue, outgoing) = mpsc::unbounded_channel();
+    let writer_task = tokio::spawn(write_loop(writer, outgoing));
+    let mut reader = BufReader::new(reader);
+
+    let (session, mut pending) = start_tcp_session(&shared, ClientHandle::queued(queue, client_addr), client_addr, first);
+    let session = Arc::new(session);
+    let in_flight = Arc::new(Semaphore::new(MAX_IN_FLIGHT));
+    let mut requests = JoinSet::new();
+
+    // Set when the connection ends for a reason other than the client leaving
+    let mut reason: Option<String> = None;
+    loop {
+        // Collect requests that are done, stop on the first that failed
+        while let Some(done) = requests.try_join_next() {
+            reason = reason.or(failure(done));
+        }
+        if reason.is_some() {
+            break;
+        }
+        if !shared.running.load(Ordering::SeqCst) {
+            break;
+        }
+        // A first frame read before the session started is served first,
+        // after that the idle timeout bounds the wait for the next frame
+        let read = read_frame_async(&mut reader);
+        let idle_timeout = shared.config().idle_timeout;
+        let result = match (pending.take(), idle_timeout) {
+            (Some(frame), _) => Ok(Some(frame)),
+            (None, Some(idle)) => match tokio::time::timeout(idle, read).await {
+                Ok(result) => result,
+                Err(_) => {
+                    reason = Some("idle timeout".to_string());
+                    break;
+                }
+            },
+            (None, None) => read.await,
+        };
+        let request = match result {
+            Ok(Some(frame)) => frame,
+            Ok(None) => break,
+            Err(e) => {
+                reason = Some(format!("read error: {}", e));
+                break;
+            }
+        };
+
+        // A client that keeps pipelining waits here for a free slot
+        let Ok(permit) = Arc::clone(&in_flight).acquire_owned().await else {
+            break;
+        };
+        let shared = Arc::clone(&shared);
+        let session = Arc::clone(&session);
+        // Handlers are plain blocking code, keep them off the async worker threads
+        requests.spawn_blocking(move || {
+            let handle = session.handle.clone();
+            let result = serve_request(&shared, &session, &request, |reply| handle.write(reply));
+            drop(permit);
+            if let Ok(Flow::Close) = result {
+                // OPT5: the reader sees EOF, replies still in progress go out
+                session.handle.shutdown_read();
+            }
+            result
+        });
+    }
+
+    // Requests still in progress finish before the socket is closed
+    while let Some(done) = requests.join_next().await {
+        reason = reason.or(failure(done));
+    }
+
+    if session.handle.is_kicked() {
+        // The operator closed the socket under us, whatever the read loop saw
+        reason = Some("kicked by operator".to_string());
+    }
+    // Let the writer flush what is queued, then close the socket
+    session.handle.close();
+    end_tcp_session(&shared, &session, reason.as_deref());
+    drop(session);
+    let _ = writer_task.await;
+}
+
+// The first frame of a connection, if it arrives within RESUME_WAIT
+async fn read_first_frame(stream: &mut TcpStream, shared: &Shared) -> io::Result<Option<Frame>> {
+    if shared.resume_tokens.is_none() {
+        return Ok(None);
+    }
+    let mut byte = [0u8; 1];
+    match tokio::time::timeout(RESUME_WAIT, stream.peek(&mut byte)).await {
+        Ok(Ok(n)) if n > 0 => read_frame_async(stream).await,
+        Ok(Ok(_)) | Err(_) => Ok(None),
+        Ok(Err(e)) => Err(e),
+    }
+}
+
+// Why a finished request ends the connection, if it does
+fn failure(done: Result<io::Result<Flow>, JoinError>) -> Option<String> {
+    match done {
+        Ok(Ok(_)) => None,
+        Ok(Err(e)) => Some(format!("write error: {}", e)),
+        Err(e) => Some(format!("request failed: {}", e)),
+    }
+}
+
+// Write queued frames in order until the connection is closed, every handle is gone
+// or the client stops reading for WRITE_TIMEOUT
+async fn write_loop(mut writer: OwnedWriteHalf, mut queue: UnboundedReceiver<Outgoing>) {
+    while let Some(outgoing) = queue.recv().await {
+        match outgoing {
+            Outgoing::Data(bytes) => {
+                if !matches!(tokio::time::timeout(WRITE_TIMEOUT, writer.write_all(&bytes)).await, Ok(Ok(()))) {
+                    break;
+                }
+            }
+            // Makes the reader task see EOF, a reply in progress still goes out
+            Outgoing::ShutdownRead => {
+                let _ = SockRef::from(writer.as_ref()).shutdown(std::net::Shutdown::Read);
+            }
+            Outgoing::Close => break,
+        }
+    }
+    let _ = SockRef::from(writer.as_ref()).shutdown(std::net::Shutdown::Both);
+}
@@ -0,0 +1,279 @@
+// Handles to connected clients, kept in the shared clients map
+//
+// The worker thread owns the read side of a TCP connection. Everything written to
+// the client goes through the handle's writer lock, so a reply from the worker and a
+// notice from another thread (e.g. shutdown) can never interleave on the wire.
+// UDP sessions get a handle too, so they show up in the client count, in OPT6 and
+// receive the same notices. The handle also carries the per-client statistics.
+// Connections of the tokio server (--async) are written by a writer task; their
+// handle only queues bytes for it, so writing never blocks the caller. TLS
+// connections are written through their TlsConnection, which has a lock of its own.
+// The admin console kicks a client through its handle; the thread serving it finds
+// out through is_kicked() and logs the disconnect with that reason.
+
+use std::io;
+use std::net::{Shutdown, SocketAddr, TcpStream, UdpSocket};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Local};
+
+#[cfg(feature = "async")]
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::protocol::{encode_datagram, encode_frame, write_frame, Frame};
+use crate::tls::TlsConnection;
+
+// How long a kick or a broadcast waits for its notice to go out. A notice stuck
+// behind a client that stopped reading keeps trying until the write timeout.
+pub(crate) const NOTICE_WAIT: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone)]
+pub struct ClientStats {
+    pub addr: SocketAddr,
+    pub protocol: &'static str,
+    pub connected_at: DateTime<Local>,
+    pub requests: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub throttled: u64,
+    pub last_activity: Instant,
+}
+
+#[derive(Clone)]
+enum Transport {
+    // The socket is a clone of the writer's, to close the connection while a
+    // write is stuck holding the writer lock
+    Tcp { writer: Arc<Mutex<TcpStream>>, socket: Arc<TcpStream> },
+    Tls(Arc<TlsConnection>),
+    Udp(Arc<UdpSocket>),
+    #[cfg(feature = "async")]
+    Queued(UnboundedSender<Outgoing>),
+}
+
+// Work for the writer task of an async connection, carried out in queue order
+#[cfg(feature = "async")]
+pub enum Outgoing {
+    Data(Vec<u8>),
+    ShutdownRead,
+    Close,
+}
+
+#[derive(Clone)]
+pub struct ClientHandle {
+    transport: Transport,
+    stats: Arc<Mutex<ClientStats>>,
+    // Kept out of stats: counted on every refused request, which must stay cheap
+    throttled: Arc<AtomicU64>,
+    kicked: Arc<AtomicBool>,
+}
+
+impl ClientHandle {
+    pub fn new(stream: &TcpStream, addr: SocketAddr) -> io::Result<Self> {
+        let transport = Transport::Tcp {
+            writer: Arc::new(Mutex::new(stream.try_clone()?)),
+            socket: Arc::new(stream.try_clone()?),
+        };
+        Ok(ClientHandle::with_transport(transport, addr))
+    }
+
+    // Handle for a TCP connection after its TLS handshake
+    pub(crate) fn tls(connection: Arc<TlsConnection>, addr: SocketAddr) -> Self {
+        ClientHandle::with_transport(Transport::Tls(connection), addr)
+    }
+
+    // Handle for a UDP session, identified by its source address
+    pub fn udp(socket: Arc<UdpSocket>, addr: SocketAddr) -> Self {
+        ClientHandle::with_transport(Transport::Udp(socket), addr)
+    }
+
+    // Handle for a connection of the tokio server, fed to its writer task
+    #[cfg(feature = "async")]
+    pub fn queued(queue: UnboundedSender<Outgoing>, addr: SocketAddr) -> Self {
+        ClientHandle::with_transport(Transport::Queued(queue), addr)
+    }
+
+    fn with_transport(transport: Transport, addr: SocketAddr) -> Self {
+        let protocol = match transport {
+            Transport::Tcp { .. } => "tcp",
+            Transport::Tls(_) => "tls",
+            Transport::Udp(_) => "udp",
+            #[cfg(feature = "async")]
+            Transport::Queued(_) => "tcp",
+        };
+        let stats = ClientStats {
+            addr,
+            protocol,
+            connected_at: Local::now(),
+            requests: 0,
+            bytes_in: 0,
+            bytes_out: 0,
+            throttled: 0,
+            last_activity: Instant::now(),
+        };
+        ClientHandle {
+            transport,
+            stats: Arc::new(Mutex::new(stats)),
+            throttled: Arc::new(AtomicU64::new(0)),
+            kicked: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    // Write a frame without touching the statistics. Over UDP this is an
+    // unsolicited datagram; UDP replies are sent by the UDP loop itself. A TCP write
+    // that fails (e.g. at the write timeout) may have left part of a frame on the
+    // wire, so it closes the connection and later writes fail at once.
+    pub fn write(&self, frame: &Frame) -> io::Result<()> {
+        match &self.transport {
+            Transport::Tcp { writer, .. } => {
+                let mut writer = writer.lock().unwrap();
+                write_frame(&mut *writer, frame).inspect_err(|_| {
+                    let _ = writer.shutdown(Shutdown::Both);
+                })
+            }
+            Transport::Tls(connection) => connection.write_all(&encode_frame(frame)?),
+            Transport::Udp(socket) => {
+                let addr = self.stats.lock().unwrap().addr;
+                socket.send_to(&encode_datagram(frame)?, addr).map(|_| ())
+            }
+            #[cfg(feature = "async")]
+            Transport::Queued(queue) => queue.send(Outgoing::Data(encode_frame(frame)?))
+                .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "connection closed")),
+        }
+    }
+
+    // Write a frame and account for it
+    pub fn send(&self, frame: &Frame) -> io::Result<()> {
+        self.write(frame)?;
+        self.record_sent(frame);
+        Ok(())
+    }
+
+    // Account for a frame written to the client
+    pub fn record_sent(&self, frame: &Frame) {
+        let mut stats = self.stats.lock().unwrap();
+        stats.bytes_out += frame.wire_len() as u64;
+        stats.last_activity = Instant::now();
+    }
+
+    // Account for a request frame read from the client
+    pub fn record_request(&self, request: &Frame) {
+        let mut stats = self.stats.lock().unwrap();
+        stats.requests += 1;
+        stats.bytes_in += request.wire_len() as u64;
+        stats.last_activity = Instant::now();
+    }
+
+    // Carry the counters of a resumed session over to this connection
+    pub fn restore(&self, previous: &ClientStats) {
+        let mut stats = self.stats.lock().unwrap();
+        stats.connected_at = previous.connected_at;
+        stats.requests += previous.requests;
+        stats.bytes_in += previous.bytes_in;
+        stats.bytes_out += previous.bytes_out;
+        self.throttled.fetch_add(previous.throttled, Ordering::SeqCst);
+    }
+
+    // Account for a request refused by the rate limiter
+    pub fn record_throttled(&self) {
+        self.throttled.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn stats(&self) -> ClientStats {
+        let mut stats = self.stats.lock().unwrap().clone();
+        stats.throttled = self.throttled.load(Ordering::SeqCst);
+        stats
+    }
+
+    // Stop reading; a worker blocked in read sees EOF but can still write its reply.
+    // UDP sessions have nothing to stop.
+    pub fn shutdown_read(&self) {
+        match &self.transport {
+            Transport::Tcp { socket, .. } => {
+                let _ = socket.shutdown(Shutdown::Read);
+            }
+            Transport::Tls(connection) => {
+                let _ = connection.socket().shutdown(Shutdown::Read);
+            }
+            Transport::Udp(_) => {}
+            #[cfg(feature = "async")]
+            Transport::Queued(queue) => {
+                let _ = queue.send(Outgoing::ShutdownRead);
+            }
+        }
+    }
+
+    // Close the connection without waiting for a write in progress, which then fails
+    pub fn close(&self) {
+        match &self.transport {
+            Transport::Tcp { socket, .. } => {
+                let _ = socket.shutdown(Shutdown::Both);
+            }
+            Transport::Tls(connection) => connection.close(),
+            Transport::Udp(_) => {}
+            #[cfg(feature = "async")]
+            Transport::Queued(queue) => {
+                let _ = queue.send(Outgoing::Close);
+            }
+        }
+    }
+
+    // Tell the client why and disconnect it. A TCP worker then reads EOF, a UDP
+    // session is dropped by the UDP loop on its next pass. The caller does not wait
+    // for the notice; if it is not through within NOTICE_WAIT the connection is
+    // closed without it.
+    pub fn kick(&self, notice: &Frame) {
+        self.kicked.store(true, Ordering::SeqCst);
+        let (sent, done) = mpsc::channel();
+        self.send_in_background(notice, sent);
+        let handle = self.clone();
+        thread::spawn(move || {
+            let _ = done.recv_timeout(NOTICE_WAIT);
+            handle.close();
+        });
+    }
+
+    // Send a frame without waiting for the client; whether it got through is
+    // reported on `sent`. Only a write that may block gets a thread of its own.
+    pub(crate) fn send_in_background(&self, frame: &Frame, sent: mpsc::Sender<bool>) {
+        if !self.write_may_block() {
+            let _ = sent.send(self.send(frame).is_ok());
+            return;
+        }
+        let (handle, frame) = (self.clone(), frame.clone());
+        thread::spawn(move || {
+            let _ = sent.send(handle.send(&frame).is_ok());
+        });
+    }
+
+    // Whether a write can wait for the client, at most the server's write timeout.
+    // UDP datagrams and the queue of an async connection never do.
+    fn write_may_block(&self) -> bool {
+        matches!(self.transport, Transport::Tcp { .. } | Transport::Tls(_))
+    }
+
+    pub fn is_kicked(&self) -> bool {
+        self.kicked.load(Ordering::SeqCst)
+    }
+}
+
+impl ClientStats {
+    // One line per client, shared by OPT6 and the periodic log
+    pub fn summary(&self, id: usize) -> String {
+        format!(
+            "Client {}: addr = {} ({}), connected at {}, requests = {}, throttled = {}, bytes in = {}, bytes out = {}, idle = {}s",
+            id,
+            self.addr,
+            self.protocol,
+            self.connected_at.format("%H:%M:%S"),
+            self.requests,
+            self.throttled,
+            self.bytes_in,
+            self.bytes_out,
+            self.last_activity.elapsed().as_secs()
+        )
+    }
+}
@@ -0,0 +1,28 @@
+// OPT4: total number of requests served so far
+
+use std::sync::atomic::Ordering;
+
+use super::{CommandHandler, Context, Outcome};
+
+pub struct Count;
+
+impl CommandHandler for Count {
+    fn handle(&self, ctx: &Context, _payload: &[u8]) -> Outcome {
+        let count = ctx.request_count.load(Ordering::SeqCst);
+        Outcome::reply(format!("requests served = {}", count))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::testing::State;
+
+    #[test]
+    fn reports_the_shared_counter() {
+        let state = State::new();
+        assert_eq!(Count.handle(&state.context(), b""), Outcome::reply("requests served = 0"));
+        state.request_count.store(42, Ordering::SeqCst);
+        assert_eq!(Count.handle(&state.context(), b""), Outcome::reply("requests served = 42"));
+    }
+}
@@ -0,0 +1,47 @@
+// HELLO: protocol version and capability handshake, see protocol::Hello
+//
+// Not a registered handler: the reply lists the opcodes of the registry, so
+// Registry::dispatch answers it itself.
+
+use super::Outcome;
+use crate::protocol::{Frame, Hello, MIN_PROTOCOL_VERSION, OP_REPLY, PROTOCOL_VERSION};
+
+pub(super) fn handshake(payload: &[u8], opcodes: Vec<u8>) -> Outcome {
+    let Some(client) = Hello::decode(payload) else {
+        return Outcome::error("malformed HELLO: expected a 2-byte protocol version");
+    };
+    if client.version < MIN_PROTOCOL_VERSION {
+        return Outcome::error(format!(
+            "protocol version {} is not supported, this server speaks versions {} to {}",
+            client.version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+        ));
+    }
+    // A newer client decides itself whether it can speak our version
+    let server = Hello { version: PROTOCOL_VERSION, opcodes };
+    Outcome::Reply(Frame::new(OP_REPLY, server.encode()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::OP_ERROR;
+
+    #[test]
+    fn answers_with_version_and_opcodes() {
+        let request = Hello { version: PROTOCOL_VERSION, opcodes: vec![1] };
+        let Outcome::Reply(reply) = handshake(&request.encode(), vec![1, 2, 8]) else {
+            panic!("expected a reply");
+        };
+        assert_eq!(reply.opcode, OP_REPLY);
+        assert_eq!(Hello::decode(&reply.payload), Some(Hello { version: PROTOCOL_VERSION, opcodes: vec![1, 2, 8] }));
+    }
+
+    #[test]
+    fn rejects_malformed_and_old_versions() {
+        let Outcome::Reply(reply) = handshake(&[1], vec![]) else { panic!("expected a reply") };
+        assert_eq!(reply.opcode, OP_ERROR);
+        let old = Hello { version: MIN_PROTOCOL_VERSION - 1, opcodes: vec![] };
+        let Outcome::Reply(reply) = handshake(&old.encode(), vec![]) else { panic!("expected a reply") };
+        assert_eq!(reply.opcode, OP_ERROR);
+    }
+}
@@ -0,0 +1,175 @@
+// Server command handlers
+//
+// Each OPT command lives in its own module and implements CommandHandler.
+// The connection thread looks the opcode up in a Registry and dispatches to it,
+// so new commands are added by registering a handler instead of growing an if/else chain.
+
+mod count;
+mod hello;
+mod quit;
+mod upper;
+mod uptime;
+mod who;
+mod whoami;
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::sync::atomic::AtomicU64;
+use std::time::Instant;
+
+use crate::clients::ClientHandle;
+use crate::protocol::{
+    Frame, OP_COUNT, OP_ERROR, OP_HELLO, OP_QUIT, OP_REPLY, OP_UPPER, OP_UPTIME, OP_WHO, OP_WHOAMI,
+};
+
+pub use count::Count;
+pub use quit::Quit;
+pub use upper::Upper;
+pub use uptime::Uptime;
+pub use who::Who;
+pub use whoami::WhoAmI;
+
+// Shared state a handler may look at, borrowed from the connection thread for one request
+pub struct Context<'a> {
+    pub client_id: usize,
+    pub peer_addr: SocketAddr,
+    pub start_time: Instant,
+    pub request_count: &'a AtomicU64,
+    pub clients: &'a Mutex<HashMap<usize, ClientHandle>>,
+}
+
+// What the connection thread should do after a command ran
+#[derive(Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Reply(Frame),
+    Close,
+}
+
+impl Outcome {
+    pub fn reply(text: impl Into<Vec<u8>>) -> Self {
+        Outcome::Reply(Frame::new(OP_REPLY, text))
+    }
+
+    pub fn error(text: impl Into<Vec<u8>>) -> Self {
+        Outcome::Reply(Frame::new(OP_ERROR, text))
+    }
+}
+
+pub trait CommandHandler: Send + Sync {
+    fn handle(&self, ctx: &Context, payload: &[u8]) -> Outcome;
+}
+
+// Opcode -> handler table
+#[derive(Default)]
+pub struct Registry {
+    handlers: HashMap<u8, Box<dyn CommandHandler>>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Registry { handlers: HashMap::new() }
+    }
+
+    // Registry with the OPT1~OPT6 commands
+    pub fn with_defaults() -> Self {
+        let mut registry = Registry::new();
+        registry.register(OP_UPPER, Upper);
+        registry.register(OP_UPTIME, Uptime);
+        registry.register(OP_WHOAMI, WhoAmI);
+        registry.register(OP_COUNT, Count);
+        registry.register(OP_QUIT, Quit);
+        registry.register(OP_WHO, Who);
+        registry
+    }
+
+    // Register a handler for an opcode, replacing any previous one
+    pub fn register(&mut self, opcode: u8, handler: impl CommandHandler + 'static) {
+        self.handlers.insert(opcode, Box::new(handler));
+    }
+
+    // Request opcodes this server answers, HELLO included, in ascending order
+    pub fn opcodes(&self) -> Vec<u8> {
+        let mut opcodes: Vec<u8> = self.handlers.keys().copied().chain([OP_HELLO]).collect();
+        opcodes.sort_unstable();
+        opcodes.dedup();
+        opcodes
+    }
+
+    pub fn dispatch(&self, ctx: &Context, request: &Frame) -> Outcome {
+        if request.opcode == OP_HELLO {
+            return hello::handshake(&request.payload, self.opcodes());
+        }
+        match self.handlers.get(&request.opcode) {
+            Some(handler) => handler.handle(ctx, &request.payload),
+            None => Outcome::error("Invalid message."),
+        }
+    }
+}
+
+// State a Context borrows from, for testing handlers without a connection
+#[cfg(test)]
+pub(crate) mod testing {
+    use std::collections::HashMap;
+    use std::net::SocketAddr;
+    use std::sync::Mutex;
+    use std::sync::atomic::AtomicU64;
+    use std::time::Instant;
+
+    use super::Context;
+    use crate::clients::ClientHandle;
+
+    pub const CLIENT_ID: usize = 7;
+
+    pub struct State {
+        pub start_time: Instant,
+        pub request_count: AtomicU64,
+        pub clients: Mutex<HashMap<usize, ClientHandle>>,
+    }
+
+    impl State {
+        pub fn new() -> Self {
+            State { start_time: Instant::now(), request_count: AtomicU64::new(0), clients: Mutex::new(HashMap::new()) }
+        }
+
+        pub fn context(&self) -> Context<'_> {
+            Context {
+                client_id: CLIENT_ID,
+                peer_addr: peer_addr(),
+                start_time: self.start_time,
+                request_count: &self.request_count,
+                clients: &self.clients,
+            }
+        }
+    }
+
+    pub fn peer_addr() -> SocketAddr {
+        "192.0.2.10:40123".parse().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::testing::State;
+    use super::*;
+
+    #[test]
+    fn unknown_opcode_is_an_error_reply() {
+        let state = State::new();
+        let outcome = Registry::with_defaults().dispatch(&state.context(), &Frame::new(0x42, ""));
+        assert_eq!(outcome, Outcome::error("Invalid message."));
+    }
+
+    #[test]
+    fn dispatch_runs_the_registered_handler() {
+        let state = State::new();
+        let outcome = Registry::with_defaults().dispatch(&state.context(), &Frame::new(OP_UPPER, "abc"));
+        assert_eq!(outcome, Outcome::reply("ABC"));
+    }
+
+    #[test]
+    fn opcodes_include_hello() {
+        assert_eq!(Registry::with_defaults().opcodes(), vec![1, 2, 3, 4, 5, 6, OP_HELLO]);
+        assert_eq!(Registry::new().opcodes(), vec![OP_HELLO]);
+    }
+}
@@ -0,0 +1,23 @@
+// OPT5: client asks to close the connection, no reply is sent
+
+use super::{CommandHandler, Context, Outcome};
+
+pub struct Quit;
+
+impl CommandHandler for Quit {
+    fn handle(&self, _ctx: &Context, _payload: &[u8]) -> Outcome {
+        Outcome::Close
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::testing::State;
+
+    #[test]
+    fn closes_without_a_reply() {
+        let state = State::new();
+        assert_eq!(Quit.handle(&state.context(), b""), Outcome::Close);
+    }
+}
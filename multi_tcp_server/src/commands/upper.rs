@@ -0,0 +1,30 @@
+// OPT1: upper-case conversion of the payload text
+
+use super::{CommandHandler, Context, Outcome};
+
+pub struct Upper;
+
+impl CommandHandler for Upper {
+    fn handle(&self, _ctx: &Context, payload: &[u8]) -> Outcome {
+        Outcome::reply(String::from_utf8_lossy(payload).to_uppercase())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::testing::State;
+
+    #[test]
+    fn converts_to_upper_case() {
+        let state = State::new();
+        assert_eq!(Upper.handle(&state.context(), b"Hello 42 world"), Outcome::reply("HELLO 42 WORLD"));
+        assert_eq!(Upper.handle(&state.context(), b""), Outcome::reply(""));
+    }
+
+    #[test]
+    fn invalid_utf8_is_replaced() {
+        let state = State::new();
+        assert_eq!(Upper.handle(&state.context(), b"a\xffb"), Outcome::reply("A\u{fffd}B"));
+    }
+}
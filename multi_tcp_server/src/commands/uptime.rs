@@ -0,0 +1,33 @@
+// OPT2: how long the server has been running, as HH:MM:SS
+
+use super::{CommandHandler, Context, Outcome};
+
+pub struct Uptime;
+
+impl CommandHandler for Uptime {
+    fn handle(&self, ctx: &Context, _payload: &[u8]) -> Outcome {
+        let elapsed = ctx.start_time.elapsed();
+        Outcome::reply(format!(
+            "run time = {:02}:{:02}:{:02}",
+            elapsed.as_secs() / 3600,
+            (elapsed.as_secs() / 60) % 60,
+            elapsed.as_secs() % 60
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::commands::testing::State;
+
+    #[test]
+    fn formats_the_time_since_start() {
+        let mut state = State::new();
+        assert_eq!(Uptime.handle(&state.context(), b""), Outcome::reply("run time = 00:00:00"));
+        state.start_time -= Duration::from_secs(65);
+        assert_eq!(Uptime.handle(&state.context(), b""), Outcome::reply("run time = 00:01:05"));
+    }
+}
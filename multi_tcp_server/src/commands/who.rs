@@ -0,0 +1,64 @@
+// OPT6: connected clients with their statistics, one line per client
+
+use super::{CommandHandler, Context, Outcome};
+
+pub struct Who;
+
+impl CommandHandler for Who {
+    fn handle(&self, ctx: &Context, _payload: &[u8]) -> Outcome {
+        // Snapshot the handles first so the clients map is not locked while formatting
+        let mut handles: Vec<_> = ctx.clients.lock().unwrap()
+            .iter()
+            .map(|(id, handle)| (*id, handle.clone()))
+            .collect();
+        handles.sort_by_key(|(id, _)| *id);
+
+        let mut lines = vec![format!("clients connected = {}", handles.len())];
+        for (id, handle) in handles {
+            let mut line = handle.stats().summary(id);
+            if id == ctx.client_id {
+                line.push_str(" (you)");
+            }
+            lines.push(line);
+        }
+        Outcome::reply(lines.join("\n"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::clients::ClientHandle;
+    use crate::commands::testing::{peer_addr, State, CLIENT_ID};
+
+    #[test]
+    fn no_clients() {
+        let state = State::new();
+        assert_eq!(Who.handle(&state.context(), b""), Outcome::reply("clients connected = 0"));
+    }
+
+    #[test]
+    fn lists_clients_in_id_order_and_marks_the_caller() {
+        let state = State::new();
+        // A UDP handle needs no peer; nothing is sent through it here
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").unwrap());
+        let other: std::net::SocketAddr = "192.0.2.20:5000".parse().unwrap();
+        state.clients.lock().unwrap().insert(CLIENT_ID + 1, ClientHandle::udp(Arc::clone(&socket), other));
+        state.clients.lock().unwrap().insert(CLIENT_ID, ClientHandle::udp(socket, peer_addr()));
+
+        let Outcome::Reply(reply) = Who.handle(&state.context(), b"") else {
+            panic!("expected a reply");
+        };
+        let text = reply.text();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], "clients connected = 2");
+        assert!(lines[1].starts_with("Client 7: addr = 192.0.2.10:40123 (udp)"), "{}", lines[1]);
+        assert!(lines[1].ends_with(" (you)"), "{}", lines[1]);
+        assert!(lines[2].starts_with("Client 8: addr = 192.0.2.20:5000 (udp)"), "{}", lines[2]);
+        assert!(!lines[2].ends_with(" (you)"), "{}", lines[2]);
+    }
+}
@@ -0,0 +1,23 @@
+// OPT3: the client's IP and port as seen by the server
+
+use super::{CommandHandler, Context, Outcome};
+
+pub struct WhoAmI;
+
+impl CommandHandler for WhoAmI {
+    fn handle(&self, ctx: &Context, _payload: &[u8]) -> Outcome {
+        Outcome::reply(format!("client IP = {}, port = {}", ctx.peer_addr.ip(), ctx.peer_addr.port()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::testing::State;
+
+    #[test]
+    fn replies_with_the_peer_address() {
+        let state = State::new();
+        assert_eq!(WhoAmI.handle(&state.context(), b""), Outcome::reply("client IP = 192.0.2.10, port = 40123"));
+    }
+}
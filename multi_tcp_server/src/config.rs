@@ -0,0 +1,469 @@
+// Server settings, taken from a TOML config file and command-line flags
+//
+// The config file uses the long option names as keys, with dashes or underscores:
+//
+//   port = 11406
+//   max-clients = 100
+//   idle_timeout = 120
+//   rate-limit = 5
+//   udp = true
+//
+// Each key is turned into the matching flag and parsed like one, so the file
+// accepts exactly what the command line does. Flags given on the command line win
+// over the file. On SIGHUP the binary parses both again and hands the result to
+// ServerHandle::reload, which applies what can change while clients stay connected
+// (see Config::apply_reload).
+
+use std::env;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::logger::{Level, LogFormat};
+use crate::ratelimit::RateLimit;
+
+pub const USAGE: &str = "\
+Usage: multi_tcp_server [options]
+
+Options:
+  --config <path> Read settings from this TOML file; SIGHUP reads it again
+  --bind <ip>     Address to listen on (default 0.0.0.0)
+  --port <port>   TCP (and UDP) port to listen on, 0 = any free port (default 11406)
+  --workers <n>   Number of worker threads serving clients (default 16)
+  --queue <n>     Accepted connections that may wait for a free worker (default 32)
+  --async         Serve TCP clients with tokio tasks instead of the worker pool
+                  (needs a build with --features async; --workers and --queue do not apply)
+  --access-list <path>
+                  Allow/deny rules in CIDR notation for incoming clients; reload with
+                  the console's reload command (default: everyone is allowed)
+  --tls-cert <path>
+                  Serve TCP clients over TLS with this PEM certificate (chain); needs --tls-key
+  --tls-key <path>
+                  PEM private key of the --tls-cert certificate
+  --tls-client-ca <path>
+                  Require client certificates signed by one of the CAs in this PEM file
+  --max-clients <n>
+                  Connected clients allowed at once, 0 = unlimited (default 0)
+  --grace <secs>  On Ctrl+C, how long to wait for clients to finish (default 5)
+  --log-interval <secs>
+                  Time between the periodic client count log lines (default 10)
+  --log-client-stats
+                  Also print per-client statistics in the periodic log
+  --idle-timeout <secs>
+                  Disconnect clients that send nothing for this long, 0 = never (default 300)
+  --resume-window <secs>
+                  How long a dropped TCP client may resume its session, 0 = never (default 60)
+  --keepalive <secs>
+                  Enable TCP keepalive, probing after this much idle time (default off)
+  --keepalive-interval <secs>
+                  Time between keepalive probes (default 10)
+  --rate-limit <n>
+                  Requests per second allowed per client, 0 = unlimited (default 0)
+  --rate-burst <n>
+                  Requests a client may send at once before the limit applies (default 2 x rate)
+  --udp           Also serve the OPT commands over UDP on the same port
+  --udp-session-timeout <secs>
+                  Forget a UDP client after this long without a datagram (default 60)
+  --metrics-port <port>
+                  Serve Prometheus metrics over HTTP at /metrics on this port (default off)
+  --log-level <level>
+                  debug, info, warn or error (default info); requests are logged at debug
+  --log-file <path>
+                  Also write log records to this file, rotated by size
+  --log-format <fmt>
+                  Format of the log file: text or json (JSON lines) (default text)
+  --log-max-bytes <n>
+                  Rotate the log file when it would grow past this size (default 10485760)
+  --log-keep <n>  Rotated log files to keep (default 5)
+  --log-payloads  Include request payloads in request records (default off)
+  -h, --help      Show this message";
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub config_file: Option<PathBuf>,
+    pub bind_address: IpAddr,
+    pub port: u16,
+    pub workers: usize,
+    pub queue_capacity: usize,
+    pub async_io: bool,
+    pub access_list: Option<PathBuf>,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub tls_client_ca: Option<PathBuf>,
+    pub max_clients: Option<usize>,
+    pub grace_period: Duration,
+    pub log_interval: Duration,
+    pub log_client_stats: bool,
+    pub idle_timeout: Option<Duration>,
+    pub resume_window: Option<Duration>,
+    pub keepalive: Option<Duration>,
+    pub keepalive_interval: Duration,
+    pub rate_limit: Option<RateLimit>,
+    pub udp: bool,
+    pub udp_session_timeout: Duration,
+    pub metrics_port: Option<u16>,
+    pub log_level: Level,
+    pub log_file: Option<PathBuf>,
+    pub log_format: LogFormat,
+    pub log_max_bytes: u64,
+    pub log_keep: usize,
+    pub log_payloads: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            config_file: None,
+            bind_address: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            port: 11406,
+            workers: 16,
+            queue_capacity: 32,
+            async_io: false,
+            access_list: None,
+            tls_cert: None,
+            tls_key: None,
+            tls_client_ca: None,
+            max_clients: None,
+            grace_period: Duration::from_secs(5),
+            log_interval: Duration::from_secs(10),
+            log_client_stats: false,
+            idle_timeout: Some(Duration::from_secs(300)),
+            resume_window: Some(Duration::from_secs(60)),
+            keepalive: None,
+            keepalive_interval: Duration::from_secs(10),
+            rate_limit: None,
+            udp: false,
+            udp_session_timeout: Duration::from_secs(60),
+            metrics_port: None,
+            log_level: Level::Info,
+            log_file: None,
+            log_format: LogFormat::Text,
+            log_max_bytes: 10 * 1024 * 1024,
+            log_keep: 5,
+            log_payloads: false,
+        }
+    }
+}
+
+impl Config {
+    pub fn from_args() -> Result<Config, String> {
+        Config::parse(env::args().skip(1))
+    }
+
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Config, String> {
+        let args: Vec<String> = args.into_iter().collect();
+        let config_file = match args.iter().position(|arg| arg == "--config") {
+            Some(i) => Some(PathBuf::from(parse_value::<String>("--config", args.get(i + 1).cloned())?)),
+            None => None,
+        };
+        // The file goes first so that the command line overrides it
+        let mut all_args = match &config_file {
+            Some(path) => file_args(path)?,
+            None => Vec::new(),
+        };
+        all_args.extend(args);
+
+        let mut config = Config::parse_flags(all_args)?;
+        config.config_file = config_file;
+        Ok(config)
+    }
+
+    fn parse_flags(args: Vec<String>) -> Result<Config, String> {
+        let mut config = Config::default();
+        let mut rate: f64 = 0.0;
+        let mut burst: Option<f64> = None;
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                // Read before the other flags, see parse
+                "--config" => {
+                    args.next();
+                }
+                "--bind" => config.bind_address = parse_value(&arg, args.next())?,
+                "--port" => config.port = parse_value(&arg, args.next())?,
+                "--workers" => {
+                    config.workers = parse_value(&arg, args.next())?;
+                    if config.workers == 0 {
+                        return Err("--workers must be at least 1".to_string());
+                    }
+                }
+                "--queue" => config.queue_capacity = parse_value(&arg, args.next())?,
+                "--async" if cfg!(feature = "async") => config.async_io = true,
+                "--async" => return Err("--async needs a server built with --features async".to_string()),
+                "--access-list" => config.access_list = Some(PathBuf::from(parse_value::<String>(&arg, args.next())?)),
+                "--tls-cert" => config.tls_cert = Some(PathBuf::from(parse_value::<String>(&arg, args.next())?)),
+                "--tls-key" => config.tls_key = Some(PathBuf::from(parse_value::<String>(&arg, args.next())?)),
+                "--tls-client-ca" => config.tls_client_ca = Some(PathBuf::from(parse_value::<String>(&arg, args.next())?)),
+                "--max-clients" => {
+                    let max: usize = parse_value(&arg, args.next())?;
+                    config.max_clients = (max > 0).then_some(max);
+                }
+                "--grace" => config.grace_period = Duration::from_secs_f64(parse_non_negative(&arg, args.next())?),
+                "--log-interval" => {
+                    config.log_interval = parse_optional_secs(&arg, args.next())?
+                        .ok_or_else(|| "--log-interval must be greater than 0".to_string())?;
+                }
+                "--log-client-stats" => config.log_client_stats = true,
+                "--idle-timeout" => config.idle_timeout = parse_optional_secs(&arg, args.next())?,
+                "--resume-window" => config.resume_window = parse_optional_secs(&arg, args.next())?,
+                "--keepalive" => config.keepalive = parse_optional_secs(&arg, args.next())?,
+                "--keepalive-interval" => {
+                    config.keepalive_interval = parse_optional_secs(&arg, args.next())?
+                        .ok_or_else(|| "--keepalive-interval must be greater than 0".to_string())?;
+                }
+                "--rate-limit" => rate = parse_non_negative(&arg, args.next())?,
+                "--rate-burst" => {
+                    let value: f64 = parse_non_negative(&arg, args.next())?;
+                    if value < 1.0 {
+                        return Err("--rate-burst must be at least 1".to_string());
+                    }
+                    burst = Some(value);
+                }
+                "--udp" => config.udp = true,
+                "--udp-session-timeout" => {
+                    config.udp_session_timeout = parse_optional_secs(&arg, args.next())?
+                        .ok_or_else(|| "--udp-session-timeout must be greater than 0".to_string())?;
+                }
+                "--metrics-port" => config.metrics_port = Some(parse_value(&arg, args.next())?),
+                "--log-level" => config.log_level = parse_value(&arg, args.next())?,
+                "--log-file" => config.log_file = Some(PathBuf::from(parse_value::<String>(&arg, args.next())?)),
+                "--log-format" => config.log_format = parse_value(&arg, args.next())?,
+                "--log-max-bytes" => config.log_max_bytes = parse_value(&arg, args.next())?,
+                "--log-keep" => config.log_keep = parse_value(&arg, args.next())?,
+                "--log-payloads" => config.log_payloads = true,
+                "-h" | "--help" => return Err(USAGE.to_string()),
+                other => return Err(format!("Unknown option: {}\n\n{}", other, USAGE)),
+            }
+        }
+        if rate > 0.0 {
+            config.rate_limit = Some(RateLimit {
+                rate,
+                burst: burst.unwrap_or((rate * 2.0).max(1.0)),
+            });
+        }
+        config.check_tls()?;
+        Ok(config)
+    }
+
+    // TLS needs both a certificate and a key, and covers the threaded TCP server only
+    pub fn check_tls(&self) -> Result<(), String> {
+        match (&self.tls_cert, &self.tls_key) {
+            (Some(_), Some(_)) => {}
+            (None, None) if self.tls_client_ca.is_some() => {
+                return Err("--tls-client-ca needs --tls-cert and --tls-key".to_string());
+            }
+            (None, None) => return Ok(()),
+            _ => return Err("--tls-cert and --tls-key go together".to_string()),
+        }
+        if self.udp {
+            return Err("UDP cannot be encrypted, drop --udp to serve over TLS".to_string());
+        }
+        if self.async_io {
+            return Err("TLS is not available with --async yet".to_string());
+        }
+        Ok(())
+    }
+
+    // Take over the settings of a reloaded config that can change while the server
+    // runs. They apply to new clients right away and to connected ones from their
+    // next request on. Returns the names of changed settings that need a restart;
+    // those keep their current values.
+    pub fn apply_reload(&mut self, new: Config) -> Vec<&'static str> {
+        let old = std::mem::replace(self, new);
+        let fixed = [
+            ("bind", old.bind_address != self.bind_address),
+            ("port", old.port != self.port),
+            ("workers", old.workers != self.workers),
+            ("queue", old.queue_capacity != self.queue_capacity),
+            ("async", old.async_io != self.async_io),
+            ("tls-cert", old.tls_cert != self.tls_cert),
+            ("tls-key", old.tls_key != self.tls_key),
+            ("tls-client-ca", old.tls_client_ca != self.tls_client_ca),
+            ("resume-window", old.resume_window != self.resume_window),
+            ("udp", old.udp != self.udp),
+            ("metrics-port", old.metrics_port != self.metrics_port),
+            ("log-level", old.log_level != self.log_level),
+            ("log-file", old.log_file != self.log_file),
+            ("log-format", old.log_format != self.log_format),
+            ("log-max-bytes", old.log_max_bytes != self.log_max_bytes),
+            ("log-keep", old.log_keep != self.log_keep),
+            ("log-payloads", old.log_payloads != self.log_payloads),
+        ];
+        self.bind_address = old.bind_address;
+        self.port = old.port;
+        self.workers = old.workers;
+        self.queue_capacity = old.queue_capacity;
+        self.async_io = old.async_io;
+        self.tls_cert = old.tls_cert;
+        self.tls_key = old.tls_key;
+        self.tls_client_ca = old.tls_client_ca;
+        self.resume_window = old.resume_window;
+        self.udp = old.udp;
+        self.metrics_port = old.metrics_port;
+        self.log_level = old.log_level;
+        self.log_file = old.log_file;
+        self.log_format = old.log_format;
+        self.log_max_bytes = old.log_max_bytes;
+        self.log_keep = old.log_keep;
+        self.log_payloads = old.log_payloads;
+        fixed.iter().filter(|(_, changed)| *changed).map(|(name, _)| *name).collect()
+    }
+}
+
+// The settings of a config file as the equivalent command-line flags
+fn file_args(path: &Path) -> Result<Vec<String>, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("Cannot read {}: {}", path.display(), e))?;
+    let table: toml::Table = text.parse().map_err(|e| format!("Invalid config file {}: {}", path.display(), e))?;
+    let mut args = Vec::new();
+    for (key, value) in table {
+        let flag = format!("--{}", key.replace('_', "-"));
+        if flag == "--config" || flag == "--help" {
+            return Err(format!("{}: '{}' cannot be set in a config file", path.display(), key));
+        }
+        match value {
+            toml::Value::Boolean(true) => args.push(flag),
+            // Switches are off unless given, so false is the same as leaving the key out
+            toml::Value::Boolean(false) => {}
+            toml::Value::String(value) => args.extend([flag, value]),
+            toml::Value::Integer(value) => args.extend([flag, value.to_string()]),
+            toml::Value::Float(value) => args.extend([flag, value.to_string()]),
+            other => return Err(format!("{}: unsupported value for '{}': {}", path.display(), key, other)),
+        }
+    }
+    Ok(args)
+}
+
+fn parse_value<T: std::str::FromStr>(flag: &str, value: Option<String>) -> Result<T, String> {
+    let value = value.ok_or_else(|| format!("Missing value for {}", flag))?;
+    value
+        .parse()
+        .map_err(|_| format!("Invalid value for {}: {}", flag, value))
+}
+
+fn parse_non_negative(flag: &str, value: Option<String>) -> Result<f64, String> {
+    let number: f64 = parse_value(flag, value)?;
+    if !number.is_finite() || number < 0.0 {
+        return Err(format!("Invalid value for {}: {}", flag, number));
+    }
+    Ok(number)
+}
+
+// Seconds where 0 means "disabled"
+fn parse_optional_secs(flag: &str, value: Option<String>) -> Result<Option<Duration>, String> {
+    let secs = parse_non_negative(flag, value)?;
+    Ok((secs > 0.0).then(|| Duration::from_secs_f64(secs)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A config file with this text, removed again when dropped
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str, text: &str) -> TempFile {
+            let path = env::temp_dir().join(format!("multi_tcp_server-{}-{}.toml", std::process::id(), name));
+            fs::write(&path, text).unwrap();
+            TempFile(path)
+        }
+
+        fn arg(&self) -> String {
+            self.0.display().to_string()
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn file_keys_become_flags() {
+        let file = TempFile::new("keys", "port = 12000\nmax_clients = 8\nrate-limit = 2.5\nudp = true\nlog-level = \"debug\"\n");
+        let config = Config::parse(args(&["--config", &file.arg()])).unwrap();
+        assert_eq!(config.port, 12000);
+        assert_eq!(config.max_clients, Some(8));
+        assert_eq!(config.rate_limit, Some(RateLimit { rate: 2.5, burst: 5.0 }));
+        assert!(config.udp);
+        assert_eq!(config.log_level, Level::Debug);
+        assert_eq!(config.config_file, Some(file.0.clone()));
+    }
+
+    #[test]
+    fn command_line_overrides_the_file() {
+        let file = TempFile::new("override", "port = 12000\nmax-clients = 8\n");
+        // Before or after --config makes no difference
+        let config = Config::parse(args(&["--port", "13000", "--config", &file.arg()])).unwrap();
+        assert_eq!(config.port, 13000);
+        assert_eq!(config.max_clients, Some(8));
+        let config = Config::parse(args(&["--config", &file.arg(), "--max-clients", "0"])).unwrap();
+        assert_eq!(config.port, 12000);
+        assert_eq!(config.max_clients, None);
+    }
+
+    #[test]
+    fn false_booleans_leave_the_switch_off() {
+        let file = TempFile::new("false", "udp = false\nport = 12000\n");
+        let config = Config::parse(args(&["--config", &file.arg()])).unwrap();
+        assert!(!config.udp);
+        assert_eq!(config.port, 12000);
+        // The command line can still turn it on
+        let config = Config::parse(args(&["--config", &file.arg(), "--udp"])).unwrap();
+        assert!(config.udp);
+    }
+
+    #[test]
+    fn unsupported_values_are_rejected() {
+        let file = TempFile::new("array", "workers = [1, 2]\n");
+        let err = Config::parse(args(&["--config", &file.arg()])).unwrap_err();
+        assert!(err.contains("unsupported value for 'workers'"), "{}", err);
+        let file = TempFile::new("table", "[log]\nlevel = \"debug\"\n");
+        let err = Config::parse(args(&["--config", &file.arg()])).unwrap_err();
+        assert!(err.contains("unsupported value for 'log'"), "{}", err);
+    }
+
+    #[test]
+    fn file_cannot_name_another_config() {
+        let file = TempFile::new("nested", "config = \"other.toml\"\n");
+        let err = Config::parse(args(&["--config", &file.arg()])).unwrap_err();
+        assert!(err.contains("'config' cannot be set in a config file"), "{}", err);
+    }
+
+    #[test]
+    fn file_values_are_checked_like_flags() {
+        let file = TempFile::new("invalid", "workers = 0\n");
+        assert_eq!(Config::parse(args(&["--config", &file.arg()])).unwrap_err(), "--workers must be at least 1");
+        let file = TempFile::new("unknown", "colour = \"blue\"\n");
+        assert!(Config::parse(args(&["--config", &file.arg()])).unwrap_err().starts_with("Unknown option: --colour"));
+    }
+
+    #[test]
+    fn reload_applies_runtime_settings() {
+        let mut config = Config::default();
+        let new = Config::parse(args(&["--max-clients", "4", "--idle-timeout", "30", "--rate-limit", "10"])).unwrap();
+        assert!(config.apply_reload(new).is_empty());
+        assert_eq!(config.max_clients, Some(4));
+        assert_eq!(config.idle_timeout, Some(Duration::from_secs(30)));
+        assert_eq!(config.rate_limit, Some(RateLimit { rate: 10.0, burst: 20.0 }));
+    }
+
+    #[test]
+    fn reload_reports_restart_settings_and_keeps_them() {
+        let mut config = Config::default();
+        let new = Config::parse(args(&["--port", "12000", "--workers", "4", "--udp", "--max-clients", "4"])).unwrap();
+        let changed = config.apply_reload(new);
+        assert_eq!(changed, ["port", "workers", "udp"]);
+        assert_eq!(config.port, 11406);
+        assert_eq!(config.workers, 16);
+        assert!(!config.udp);
+        // The rest still applies
+        assert_eq!(config.max_clients, Some(4));
+    }
+}
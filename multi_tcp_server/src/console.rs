@@ -0,0 +1,97 @@
+// Operator console on the server's stdin
+//
+// One command per line:
+//   list              connected clients with their address and connect time
+//   kick <id>         notify a client and disconnect it
+//   broadcast <text>  send a notice (opcode 0x84) to every client
+//   stats             live server statistics
+//   reload            read the config file and access list again, like SIGHUP
+//   help              this list
+//
+// Console output goes straight to stdout; kicks and broadcasts are also logged.
+// The console stops quietly when stdin is closed, e.g. when the server runs detached.
+
+use std::io::{self, BufRead};
+
+use multi_tcp_server::config::Config;
+use multi_tcp_server::ServerHandle;
+
+const HELP: &str = "\
+Commands:
+  list              list connected clients
+  kick <id>         disconnect a client
+  broadcast <text>  send a notice to every client
+  stats             show live server statistics
+  reload            reload the config file and the access list
+  help              show this list";
+
+pub fn run(server: &ServerHandle) {
+    let stdin = io::stdin();
+    for line in stdin.lock().lines() {
+        let Ok(line) = line else { break };
+        if !server.is_running() {
+            break;
+        }
+        let line = line.trim();
+        let (command, arg) = match line.split_once(char::is_whitespace) {
+            Some((command, arg)) => (command, arg.trim()),
+            None => (line, ""),
+        };
+        match command {
+            "" => {}
+            "list" => list(server),
+            "kick" => match arg.parse::<usize>() {
+                Ok(id) => {
+                    if !server.kick(id) {
+                        println!("No client with id {}", id);
+                    }
+                }
+                Err(_) => println!("Usage: kick <id>"),
+            },
+            "broadcast" if !arg.is_empty() => {
+                server.broadcast(arg);
+            }
+            "broadcast" => println!("Usage: broadcast <text>"),
+            "stats" => stats(server),
+            "reload" => reload(server),
+            "help" => println!("{}", HELP),
+            other => println!("Unknown command '{}'. Type 'help' for the list of commands.", other),
+        }
+    }
+}
+
+// Parse the command line and --config file again and apply the result; the
+// outcome is logged either way
+pub fn reload(server: &ServerHandle) {
+    match Config::from_args() {
+        Ok(config) => {
+            let _ = server.reload(config);
+        }
+        Err(e) => server.logger().error(format_args!("Configuration not reloaded: {}", e)),
+    }
+}
+
+fn list(server: &ServerHandle) {
+    let clients = server.clients();
+    println!("{} client(s) connected", clients.len());
+    for (id, stats) in clients {
+        println!("  {:>4}  {:<24} {}  connected at {}",
+            id, stats.addr, stats.protocol, stats.connected_at.format("%H:%M:%S"));
+    }
+}
+
+fn stats(server: &ServerHandle) {
+    let stats = server.stats();
+    let uptime = stats.uptime.as_secs();
+    println!("uptime            = {:02}:{:02}:{:02}", uptime / 3600, (uptime / 60) % 60, uptime % 60);
+    println!("clients connected = {}", stats.clients);
+    println!("requests handled  = {}", stats.requests);
+    println!("throttled         = {}", stats.throttled);
+    match stats.pool {
+        Some((busy, size, queued)) => {
+            println!("busy workers      = {} of {}", busy, size);
+            println!("queue depth       = {}", queued);
+        }
+        None => println!("workers           = tokio tasks (--async)"),
+    }
+}
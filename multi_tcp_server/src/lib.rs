@@ -0,0 +1,27 @@
+// OPT server library
+//
+// The server behind the multi_tcp_server binary, for embedding in tests and tools:
+//
+//     let server = Server::builder().bind("127.0.0.1:0".parse().unwrap()).start()?;
+//     println!("listening on port {}", server.port());
+//     server.shutdown()?;
+//
+// Commands are CommandHandler implementations registered per opcode, see commands.
+
+pub mod access;
+#[cfg(feature = "async")]
+mod async_server;
+pub mod clients;
+pub mod commands;
+pub mod config;
+pub mod logger;
+mod metrics;
+mod pool;
+pub mod protocol;
+pub mod ratelimit;
+mod resume;
+mod server;
+pub mod tls;
+mod udp;
+
+pub use server::{Server, ServerBuilder, ServerHandle, ServerStats};
@@ -0,0 +1,251 @@
+// Leveled, structured logging for the server
+//
+// Every record goes to stdout in the assignment's "[Time: HH:MM:SS] ..." form.
+// With --log-file it is also appended to a file, as plain text or as JSON lines,
+// carrying the client id, peer address and opcode when the event has them.
+// The file is rotated once it grows past --log-max-bytes: app.log becomes
+// app.log.1, app.log.1 becomes app.log.2, and so on up to --log-keep files.
+
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use chrono::{Local, SecondsFormat};
+
+use crate::config::Config;
+use crate::protocol::opcode_name;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl Level {
+    fn as_str(self) -> &'static str {
+        match self {
+            Level::Debug => "debug",
+            Level::Info => "info",
+            Level::Warn => "warn",
+            Level::Error => "error",
+        }
+    }
+}
+
+impl FromStr for Level {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "debug" => Ok(Level::Debug),
+            "info" => Ok(Level::Info),
+            "warn" | "warning" => Ok(Level::Warn),
+            "error" => Ok(Level::Error),
+            other => Err(format!("unknown log level: {}", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            other => Err(format!("unknown log format: {}", other)),
+        }
+    }
+}
+
+// Structured context of a record; all fields are optional
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Fields<'a> {
+    pub client_id: Option<usize>,
+    pub peer: Option<SocketAddr>,
+    pub opcode: Option<u8>,
+    pub payload: Option<&'a str>,
+}
+
+impl<'a> Fields<'a> {
+    pub fn client(client_id: usize, peer: SocketAddr) -> Self {
+        Fields { client_id: Some(client_id), peer: Some(peer), ..Fields::default() }
+    }
+
+    pub fn peer(peer: SocketAddr) -> Self {
+        Fields { peer: Some(peer), ..Fields::default() }
+    }
+
+    pub fn opcode(self, opcode: u8) -> Self {
+        Fields { opcode: Some(opcode), ..self }
+    }
+
+    pub fn payload(self, payload: &'a str) -> Self {
+        Fields { payload: Some(payload), ..self }
+    }
+}
+
+struct FileSink {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_bytes: u64,
+    keep: usize,
+}
+
+impl FileSink {
+    fn open(path: &Path, max_bytes: u64, keep: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(FileSink { path: path.to_path_buf(), file, size, max_bytes, keep })
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 + 1 > self.max_bytes {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{}", line)?;
+        self.size += line.len() as u64 + 1;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let rotated = |n: usize| {
+            let mut name = self.path.clone().into_os_string();
+            name.push(format!(".{}", n));
+            PathBuf::from(name)
+        };
+        if self.keep == 0 {
+            // Nothing to keep: start the current file over
+            self.file = File::create(&self.path)?;
+        } else {
+            let _ = fs::remove_file(rotated(self.keep));
+            for n in (1..self.keep).rev() {
+                let _ = fs::rename(rotated(n), rotated(n + 1));
+            }
+            fs::rename(&self.path, rotated(1))?;
+            self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        }
+        self.size = 0;
+        Ok(())
+    }
+}
+
+pub struct Logger {
+    level: Level,
+    format: LogFormat,
+    log_payloads: bool,
+    file: Option<Mutex<FileSink>>,
+}
+
+impl Logger {
+    pub fn new(config: &Config) -> io::Result<Self> {
+        let file = match &config.log_file {
+            Some(path) => Some(Mutex::new(FileSink::open(path, config.log_max_bytes, config.log_keep)?)),
+            None => None,
+        };
+        Ok(Logger {
+            level: config.log_level,
+            format: config.log_format,
+            log_payloads: config.log_payloads,
+            file,
+        })
+    }
+
+    // Whether request payloads may be logged at all
+    pub fn log_payloads(&self) -> bool {
+        self.log_payloads
+    }
+
+    pub fn enabled(&self, level: Level) -> bool {
+        level >= self.level
+    }
+
+    pub fn log(&self, level: Level, fields: Fields, message: impl fmt::Display) {
+        if !self.enabled(level) {
+            return;
+        }
+        let now = Local::now();
+        let message = message.to_string();
+        let payload = fields.payload.filter(|_| self.log_payloads);
+
+        match payload {
+            Some(payload) => println!("[Time: {}] {} Payload: {}", now.format("%H:%M:%S"), message, payload),
+            None => println!("[Time: {}] {}", now.format("%H:%M:%S"), message),
+        }
+
+        if let Some(file) = &self.file {
+            let timestamp = now.to_rfc3339_opts(SecondsFormat::Millis, false);
+            let line = match self.format {
+                LogFormat::Text => text_line(&timestamp, level, &fields, payload, &message),
+                LogFormat::Json => json_line(&timestamp, level, &fields, payload, &message),
+            };
+            if let Err(e) = file.lock().unwrap().write_line(&line) {
+                eprintln!("Failed to write log file: {}", e);
+            }
+        }
+    }
+
+    pub fn info(&self, message: impl fmt::Display) {
+        self.log(Level::Info, Fields::default(), message);
+    }
+
+    pub fn warn(&self, message: impl fmt::Display) {
+        self.log(Level::Warn, Fields::default(), message);
+    }
+
+    pub fn error(&self, message: impl fmt::Display) {
+        self.log(Level::Error, Fields::default(), message);
+    }
+}
+
+fn text_line(timestamp: &str, level: Level, fields: &Fields, payload: Option<&str>, message: &str) -> String {
+    let mut line = format!("{} {:<5}", timestamp, level.as_str().to_ascii_uppercase());
+    if let Some(id) = fields.client_id {
+        line.push_str(&format!(" client={}", id));
+    }
+    if let Some(peer) = fields.peer {
+        line.push_str(&format!(" peer={}", peer));
+    }
+    if let Some(opcode) = fields.opcode {
+        line.push_str(&format!(" opcode={}", opcode_name(opcode)));
+    }
+    line.push(' ');
+    line.push_str(message);
+    if let Some(payload) = payload {
+        line.push_str(&format!(" payload={:?}", payload));
+    }
+    line
+}
+
+fn json_line(timestamp: &str, level: Level, fields: &Fields, payload: Option<&str>, message: &str) -> String {
+    let mut record = serde_json::Map::new();
+    record.insert("ts".into(), timestamp.into());
+    record.insert("level".into(), level.as_str().into());
+    record.insert("msg".into(), message.into());
+    if let Some(id) = fields.client_id {
+        record.insert("client_id".into(), id.into());
+    }
+    if let Some(peer) = fields.peer {
+        record.insert("peer".into(), peer.to_string().into());
+    }
+    if let Some(opcode) = fields.opcode {
+        record.insert("opcode".into(), opcode_name(opcode).into());
+    }
+    if let Some(payload) = payload {
+        record.insert("payload".into(), payload.into());
+    }
+    serde_json::Value::Object(record).to_string()
+}
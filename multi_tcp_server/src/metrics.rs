@@ -0,0 +1,171 @@
+// Prometheus metrics for the OPT server
+//
+// Counters and latency histograms are updated by the worker threads. A small
+// HTTP listener on its own port serves them in the Prometheus text format at
+// GET /metrics; every scrape is answered on the metrics thread and then closed.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::protocol::opcode_name;
+
+// Upper bounds of the latency buckets, in seconds
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.5, 1.0,
+];
+
+#[derive(Default)]
+struct Histogram {
+    counts: [u64; LATENCY_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, secs: f64) {
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(self.counts.iter_mut()) {
+            if secs <= *bound {
+                *count += 1;
+            }
+        }
+        self.sum += secs;
+        self.count += 1;
+    }
+}
+
+// Values owned by the rest of the server, sampled at scrape time
+pub struct Gauges {
+    pub connected_clients: usize,
+    pub queue_depth: usize,
+    pub uptime: Duration,
+    pub throttled_requests: u64,
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    requests: Mutex<BTreeMap<&'static str, u64>>,
+    latency: Mutex<BTreeMap<&'static str, Histogram>>,
+    accept_errors: Mutex<u64>,
+    rejected_connections: Mutex<u64>,
+}
+
+impl Metrics {
+    pub fn record_request(&self, opcode: u8) {
+        *self.requests.lock().unwrap().entry(opcode_name(opcode)).or_insert(0) += 1;
+    }
+
+    pub fn observe_latency(&self, opcode: u8, elapsed: Duration) {
+        self.latency.lock().unwrap()
+            .entry(opcode_name(opcode))
+            .or_default()
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub fn record_accept_error(&self) {
+        *self.accept_errors.lock().unwrap() += 1;
+    }
+
+    pub fn record_rejected(&self) {
+        *self.rejected_connections.lock().unwrap() += 1;
+    }
+
+    // Prometheus text exposition format, version 0.0.4
+    pub fn render(&self, gauges: &Gauges) -> String {
+        let mut out = String::new();
+
+        let _ = writeln!(out, "# HELP opt_server_connected_clients Clients currently connected.");
+        let _ = writeln!(out, "# TYPE opt_server_connected_clients gauge");
+        let _ = writeln!(out, "opt_server_connected_clients {}", gauges.connected_clients);
+
+        let _ = writeln!(out, "# HELP opt_server_queue_depth Accepted connections waiting for a worker.");
+        let _ = writeln!(out, "# TYPE opt_server_queue_depth gauge");
+        let _ = writeln!(out, "opt_server_queue_depth {}", gauges.queue_depth);
+
+        let _ = writeln!(out, "# HELP opt_server_uptime_seconds Time since the server started (OPT2).");
+        let _ = writeln!(out, "# TYPE opt_server_uptime_seconds gauge");
+        let _ = writeln!(out, "opt_server_uptime_seconds {:.3}", gauges.uptime.as_secs_f64());
+
+        let _ = writeln!(out, "# HELP opt_server_requests_total Requests served, by opcode.");
+        let _ = writeln!(out, "# TYPE opt_server_requests_total counter");
+        for (label, count) in self.requests.lock().unwrap().iter() {
+            let _ = writeln!(out, "opt_server_requests_total{{opcode=\"{}\"}} {}", label, count);
+        }
+
+        let _ = writeln!(out, "# HELP opt_server_throttled_requests_total Requests refused by the rate limiter.");
+        let _ = writeln!(out, "# TYPE opt_server_throttled_requests_total counter");
+        let _ = writeln!(out, "opt_server_throttled_requests_total {}", gauges.throttled_requests);
+
+        let _ = writeln!(out, "# HELP opt_server_accept_errors_total Failed accept() calls.");
+        let _ = writeln!(out, "# TYPE opt_server_accept_errors_total counter");
+        let _ = writeln!(out, "opt_server_accept_errors_total {}", *self.accept_errors.lock().unwrap());
+
+        let _ = writeln!(out, "# HELP opt_server_rejected_connections_total Clients refused by the access list.");
+        let _ = writeln!(out, "# TYPE opt_server_rejected_connections_total counter");
+        let _ = writeln!(out, "opt_server_rejected_connections_total {}", *self.rejected_connections.lock().unwrap());
+
+        let _ = writeln!(out, "# HELP opt_server_request_duration_seconds Time from reading a request to sending its reply.");
+        let _ = writeln!(out, "# TYPE opt_server_request_duration_seconds histogram");
+        for (label, histogram) in self.latency.lock().unwrap().iter() {
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(histogram.counts.iter()) {
+                let _ = writeln!(out, "opt_server_request_duration_seconds_bucket{{opcode=\"{}\",le=\"{}\"}} {}", label, bound, count);
+            }
+            let _ = writeln!(out, "opt_server_request_duration_seconds_bucket{{opcode=\"{}\",le=\"+Inf\"}} {}", label, histogram.count);
+            let _ = writeln!(out, "opt_server_request_duration_seconds_sum{{opcode=\"{}\"}} {}", label, histogram.sum);
+            let _ = writeln!(out, "opt_server_request_duration_seconds_count{{opcode=\"{}\"}} {}", label, histogram.count);
+        }
+
+        out
+    }
+}
+
+// Serve scrapes until `running` goes false; the connection that wakes the listener
+// up after that is not answered. `gauges` samples the live server state.
+pub fn serve(listener: TcpListener, running: &AtomicBool, metrics: &Metrics, gauges: impl Fn() -> Gauges) {
+    for stream in listener.incoming() {
+        if !running.load(Ordering::SeqCst) {
+            break;
+        }
+        let Ok(stream) = stream else { continue };
+        let _ = handle_scrape(stream, metrics, &gauges);
+    }
+}
+
+fn handle_scrape(mut stream: TcpStream, metrics: &Metrics, gauges: &impl Fn() -> Gauges) -> io::Result<()> {
+    // A slow or silent scraper must not block the next one for long
+    stream.set_read_timeout(Some(Duration::from_secs(2)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(2)))?;
+
+    // Only the request line matters; read until the end of the headers
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < 8192 {
+        let n = stream.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+    let request = String::from_utf8_lossy(&request);
+    let mut parts = request.lines().next().unwrap_or("").split_whitespace();
+    let (method, path) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+
+    let (status, content_type, body) = if method == "GET" && (path == "/metrics" || path.starts_with("/metrics?")) {
+        ("200 OK", "text/plain; version=0.0.4", metrics.render(&gauges()))
+    } else {
+        ("404 Not Found", "text/plain", "Not Found\n".to_string())
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
@@ -0,0 +1,83 @@
+// Fixed-size worker pool with a bounded job queue
+//
+// Accepted connections are queued as jobs. `workers` threads take jobs off the
+// queue; at most `queue_capacity` jobs wait for a free worker. When the queue is
+// full try_execute hands the job back so the caller can refuse the connection.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+pub type Job = Box<dyn FnOnce() + Send + 'static>;
+
+pub struct WorkerPool {
+    sender: SyncSender<Job>,
+    queued: Arc<AtomicUsize>,
+    busy: Arc<AtomicUsize>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl WorkerPool {
+    pub fn new(workers: usize, queue_capacity: usize) -> Self {
+        let (sender, receiver) = mpsc::sync_channel::<Job>(queue_capacity);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let queued = Arc::new(AtomicUsize::new(0));
+        let busy = Arc::new(AtomicUsize::new(0));
+
+        let workers = (0..workers.max(1))
+            .map(|n| {
+                let receiver = Arc::clone(&receiver);
+                let queued = Arc::clone(&queued);
+                let busy = Arc::clone(&busy);
+                thread::Builder::new()
+                    .name(format!("worker-{}", n))
+                    .spawn(move || worker_loop(receiver, queued, busy))
+                    .expect("Failed to spawn worker thread")
+            })
+            .collect();
+
+        WorkerPool { sender, queued, busy, workers }
+    }
+
+    // Queue a job, or give it back if every worker is busy and the queue is full
+    pub fn try_execute(&self, job: Job) -> Result<(), Job> {
+        // Count before sending so a worker never sees the job before it is counted
+        self.queued.fetch_add(1, Ordering::SeqCst);
+        match self.sender.try_send(job) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(job)) | Err(TrySendError::Disconnected(job)) => {
+                self.queued.fetch_sub(1, Ordering::SeqCst);
+                Err(job)
+            }
+        }
+    }
+
+    // Jobs accepted but not yet picked up by a worker
+    pub fn queue_depth(&self) -> usize {
+        self.queued.load(Ordering::SeqCst)
+    }
+
+    // Workers currently running a job
+    pub fn busy_workers(&self) -> usize {
+        self.busy.load(Ordering::SeqCst)
+    }
+
+    pub fn size(&self) -> usize {
+        self.workers.len()
+    }
+}
+
+fn worker_loop(receiver: Arc<Mutex<Receiver<Job>>>, queued: Arc<AtomicUsize>, busy: Arc<AtomicUsize>) {
+    loop {
+        // Hold the lock only while waiting for the next job, not while running it
+        let job = match receiver.lock().unwrap().recv() {
+            Ok(job) => job,
+            Err(_) => break, // pool dropped
+        };
+        queued.fetch_sub(1, Ordering::SeqCst);
+        busy.fetch_add(1, Ordering::SeqCst);
+        job();
+        busy.fetch_sub(1, Ordering::SeqCst);
+    }
+}
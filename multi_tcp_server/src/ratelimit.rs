@@ -0,0 +1,100 @@
+// Token bucket used to rate-limit the requests of one client
+//
+// The bucket holds up to `burst` tokens and refills at `rate` tokens per second.
+// Every request takes one token; a request that finds the bucket empty is throttled.
+
+use std::time::Instant;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    pub rate: f64,
+    pub burst: f64,
+}
+
+pub struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    // Starts full, so a new client can send a burst right away
+    pub fn new(limit: RateLimit) -> Self {
+        TokenBucket {
+            limit,
+            tokens: limit.burst,
+            last_refill: Instant::now(),
+        }
+    }
+
+    pub fn limit(&self) -> RateLimit {
+        self.limit
+    }
+
+    pub fn try_acquire(&mut self) -> bool {
+        self.try_acquire_at(Instant::now())
+    }
+
+    fn try_acquire_at(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.limit.rate).min(self.limit.burst);
+        self.last_refill = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn bucket(rate: f64, burst: f64) -> (TokenBucket, Instant) {
+        let bucket = TokenBucket::new(RateLimit { rate, burst });
+        let start = bucket.last_refill;
+        (bucket, start)
+    }
+
+    #[test]
+    fn burst_is_available_at_once() {
+        let (mut bucket, start) = bucket(1.0, 5.0);
+        for _ in 0..5 {
+            assert!(bucket.try_acquire_at(start));
+        }
+        assert!(!bucket.try_acquire_at(start));
+    }
+
+    #[test]
+    fn refills_at_the_rate() {
+        let (mut bucket, start) = bucket(10.0, 2.0);
+        assert!(bucket.try_acquire_at(start));
+        assert!(bucket.try_acquire_at(start));
+        assert!(!bucket.try_acquire_at(start));
+        // 50 ms at 10 per second is half a token
+        assert!(!bucket.try_acquire_at(start + Duration::from_millis(50)));
+        assert!(bucket.try_acquire_at(start + Duration::from_millis(100)));
+        assert!(!bucket.try_acquire_at(start + Duration::from_millis(100)));
+    }
+
+    #[test]
+    fn refill_stops_at_the_burst() {
+        let (mut bucket, start) = bucket(10.0, 3.0);
+        let later = start + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert!(bucket.try_acquire_at(later));
+        }
+        assert!(!bucket.try_acquire_at(later));
+    }
+
+    #[test]
+    fn earlier_instant_does_not_refill() {
+        let (mut bucket, start) = bucket(10.0, 1.0);
+        let later = start + Duration::from_secs(1);
+        assert!(bucket.try_acquire_at(later));
+        assert!(!bucket.try_acquire_at(start));
+    }
+}
@@ -0,0 +1,52 @@
+// Session resumption for TCP clients
+//
+// Every TCP session gets a random token, sent to the client in an OP_SESSION frame
+// right after connect. When the connection drops, the session's id and statistics
+// are parked under that token for the resume window. A new connection that starts
+// with OP_RESUME and the token within the window takes the parked session over;
+// after that the token is gone until the session is parked again.
+// Sessions that end on purpose (OPT5, kick, server shutdown) are not parked.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::clients::ClientStats;
+
+// A session waiting to be resumed
+pub(crate) struct Parked {
+    pub(crate) client_id: usize,
+    pub(crate) stats: ClientStats,
+    since: Instant,
+}
+
+pub(crate) struct ResumeTokens {
+    window: Duration,
+    parked: Mutex<HashMap<String, Parked>>,
+}
+
+impl ResumeTokens {
+    pub(crate) fn new(window: Duration) -> Self {
+        ResumeTokens { window, parked: Mutex::new(HashMap::new()) }
+    }
+
+    pub(crate) fn new_token() -> String {
+        format!("{:032x}", rand::random::<u128>())
+    }
+
+    pub(crate) fn park(&self, token: &str, client_id: usize, stats: ClientStats) {
+        let parked = Parked { client_id, stats, since: Instant::now() };
+        self.parked.lock().unwrap().insert(token.to_string(), parked);
+    }
+
+    // The session parked under this token, if it is still within the window
+    pub(crate) fn take(&self, token: &str) -> Option<Parked> {
+        let parked = self.parked.lock().unwrap().remove(token)?;
+        (parked.since.elapsed() < self.window).then_some(parked)
+    }
+
+    // Forget sessions whose window has passed
+    pub(crate) fn prune(&self) {
+        self.parked.lock().unwrap().retain(|_, parked| parked.since.elapsed() < self.window);
+    }
+}
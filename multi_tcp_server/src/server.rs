@@ -0,0 +1,1004 @@
+// The OPT server: listener setup, connection handling and shutdown
+//
+// Server::builder() collects the bind address, limits and command handlers;
+// start() binds, spawns the background threads and the accept loop, and returns a
+// ServerHandle. The handle reports the bound address (useful with port 0), exposes
+// the operations of the admin console and shuts the server down. The binary is a
+// thin wrapper that adds Ctrl+C handling and the console on stdin.
+//
+// Each TCP connection is read by one worker of the connection pool, which hands the
+// requests to a second pool, so a client that pipelines gets its requests served
+// concurrently. Up to MAX_IN_FLIGHT of them per connection; beyond that, or when the
+// request pool is full, the connection's worker serves the request itself and reads
+// no further until it is done.
+
+use std::collections::HashMap;
+use std::io::{self, ErrorKind, Read};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use socket2::{SockRef, TcpKeepalive};
+
+use crate::access::AccessList;
+use crate::clients::{ClientHandle, ClientStats, NOTICE_WAIT};
+use crate::commands::{CommandHandler, Context, Outcome, Registry};
+use crate::config::Config;
+use crate::logger::{Fields, Level, Logger};
+use crate::metrics::{self, Gauges, Metrics};
+use crate::pool::WorkerPool;
+use crate::protocol::{
+    opcode_name, read_frame, write_frame, Frame, OP_BUSY, OP_ERROR, OP_HELLO, OP_KICK, OP_NOTICE, OP_QUIT, OP_REPLY, OP_RESUME, OP_SESSION,
+    OP_SHUTDOWN,
+};
+use crate::ratelimit::{RateLimit, TokenBucket};
+use crate::resume::{Parked, ResumeTokens};
+use crate::tls::{TlsConnection, TlsReader, TlsSettings};
+use crate::udp;
+
+// State shared by the accept loop, the worker threads and the background threads
+pub(crate) struct Shared {
+    pub(crate) start_time: Instant,
+    pub(crate) request_count: AtomicU64,
+    pub(crate) throttled_count: AtomicU64,
+    pub(crate) client_id_counter: AtomicUsize,
+    pub(crate) clients: Mutex<HashMap<usize, ClientHandle>>,
+    pub(crate) running: AtomicBool,
+    pub(crate) registry: Registry,
+    // Replaced in part by ServerHandle::reload, read it through config()
+    pub(crate) config: RwLock<Config>,
+    pub(crate) metrics: Metrics,
+    pub(crate) log: Arc<Logger>,
+    // Empty (everyone allowed) without --access-list
+    pub(crate) access: RwLock<AccessList>,
+    // None when session resumption is off
+    pub(crate) resume_tokens: Option<ResumeTokens>,
+    // None when TCP clients are served in plaintext
+    pub(crate) tls: Option<TlsSettings>,
+    // Serves the requests of the threaded TCP connections; None with --async
+    pub(crate) requests: Option<WorkerPool>,
+}
+
+impl Shared {
+    // Don't hold on to the guard across blocking calls, a reload waits for it
+    pub(crate) fn config(&self) -> RwLockReadGuard<'_, Config> {
+        self.config.read().unwrap()
+    }
+}
+
+// How long a new TCP connection is given to send OP_RESUME before it counts as a new session
+pub(crate) const RESUME_WAIT: Duration = Duration::from_millis(200);
+
+// Requests of one connection that may be served at the same time
+pub(crate) const MAX_IN_FLIGHT: usize = 32;
+
+// A client that stops reading gets this long to make room for a frame before the
+// write fails and the connection ends
+pub(crate) const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct Server;
+
+impl Server {
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder {
+            config: Config::default(),
+            registry: Registry::with_defaults(),
+            log: None,
+            tls: None,
+        }
+    }
+}
+
+// Settings for a server about to start. Starts from Config::default() and the
+// OPT1~OPT6 handlers; config() replaces every setting, so call it before the others.
+pub struct ServerBuilder {
+    config: Config,
+    registry: Registry,
+    log: Option<Logger>,
+    tls: Option<TlsSettings>,
+}
+
+impl ServerBuilder {
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = config;
+        self
+    }
+
+    // Address to listen on; port 0 picks a free port, see ServerHandle::local_addr
+    pub fn bind(mut self, addr: SocketAddr) -> Self {
+        self.config.bind_address = addr.ip();
+        self.config.port = addr.port();
+        self
+    }
+
+    pub fn workers(mut self, workers: usize) -> Self {
+        self.config.workers = workers.max(1);
+        self
+    }
+
+    pub fn queue_capacity(mut self, queue_capacity: usize) -> Self {
+        self.config.queue_capacity = queue_capacity;
+        self
+    }
+
+    pub fn idle_timeout(mut self, idle_timeout: Option<Duration>) -> Self {
+        self.config.idle_timeout = idle_timeout;
+        self
+    }
+
+    pub fn rate_limit(mut self, rate_limit: Option<RateLimit>) -> Self {
+        self.config.rate_limit = rate_limit;
+        self
+    }
+
+    // Allow/deny rules file, see access; ServerHandle::reload reads it again
+    pub fn access_list(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.access_list = Some(path.into());
+        self
+    }
+
+    pub fn grace_period(mut self, grace_period: Duration) -> Self {
+        self.config.grace_period = grace_period;
+        self
+    }
+
+    // Serve TCP clients over TLS, instead of loading --tls-cert and --tls-key
+    pub fn tls(mut self, settings: TlsSettings) -> Self {
+        self.tls = Some(settings);
+        self
+    }
+
+    // Add a command, or replace one of the defaults
+    pub fn handler(mut self, opcode: u8, handler: impl CommandHandler + 'static) -> Self {
+        self.registry.register(opcode, handler);
+        self
+    }
+
+    // Log through this logger instead of one built from the config
+    pub fn logger(mut self, log: Logger) -> Self {
+        self.log = Some(log);
+        self
+    }
+
+    // Bind and start serving in the background
+    pub fn start(self) -> io::Result<ServerHandle> {
+        let config = self.config;
+        let log = Arc::new(match self.log {
+            Some(log) => log,
+            None => Logger::new(&config)?,
+        });
+
+        let access = match &config.access_list {
+            Some(path) => AccessList::load(path)?,
+            None => AccessList::default(),
+        };
+        let tls = match (self.tls, &config.tls_cert, &config.tls_key) {
+            (Some(settings), _, _) => Some(settings),
+            (None, Some(cert), Some(key)) => Some(TlsSettings::from_files(cert, key, config.tls_client_ca.as_deref())?),
+            _ => None,
+        };
+        if tls.is_some() && (config.udp || config.async_io) {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "TLS works with the threaded TCP server only, not with UDP or --async"));
+        }
+
+        let listener = TcpListener::bind((config.bind_address, config.port))?;
+        let local_addr = listener.local_addr()?;
+        // UDP shares the port TCP ended up with
+        let udp_socket = if config.udp { Some(UdpSocket::bind(local_addr)?) } else { None };
+        log.info(format_args!("Server started on port {}. Waiting for clients...", local_addr.port()));
+        if let Some(path) = &config.access_list {
+            log.info(format_args!("Access list {}: {} allow, {} deny rule(s)",
+                path.display(), access.allow_rules(), access.deny_rules()));
+        }
+        if let Some(tls) = &tls {
+            let clients = if tls.client_auth() { "client certificates required" } else { "no client certificates" };
+            log.info(format_args!("TLS enabled for TCP clients ({})", clients));
+        }
+
+        let shared = Arc::new(Shared {
+            start_time: Instant::now(),
+            request_count: AtomicU64::new(0),
+            throttled_count: AtomicU64::new(0),
+            client_id_counter: AtomicUsize::new(1),
+            clients: Mutex::new(HashMap::new()),
+            running: AtomicBool::new(true),
+            registry: self.registry,
+            config: RwLock::new(config.clone()),
+            metrics: Metrics::default(),
+            log,
+            access: RwLock::new(access),
+            resume_tokens: config.resume_window.map(ResumeTokens::new),
+            tls,
+            requests: (!config.async_io).then(|| WorkerPool::new(config.workers, config.queue_capacity)),
+        });
+        // The tokio server (--async) runs each connection as a task instead of on a pool worker
+        let pool = if config.async_io {
+            shared.log.info("Async mode: serving TCP clients with tokio tasks");
+            None
+        } else {
+            let pool = Arc::new(WorkerPool::new(config.workers, config.queue_capacity));
+            shared.log.info(format_args!("Worker pool: {} workers, queue capacity {}, the same again for requests",
+                pool.size(), config.queue_capacity));
+            Some(pool)
+        };
+
+        // UDP variant of the service on the same port, if enabled
+        if let Some(udp_socket) = udp_socket {
+            shared.log.info(format_args!("Serving UDP on port {}", local_addr.port()));
+            let shared = Arc::clone(&shared);
+            thread::spawn(move || udp::serve(udp_socket, shared));
+        }
+
+        // Metrics endpoint on its own port, if enabled; stops with the accept loop
+        let mut metrics_server = None;
+        if let Some(metrics_port) = config.metrics_port {
+            let metrics_listener = TcpListener::bind((config.bind_address, metrics_port))?;
+            let metrics_addr = metrics_listener.local_addr()?;
+            shared.log.info(format_args!("Metrics available at http://{}/metrics", metrics_addr));
+            let shared = Arc::clone(&shared);
+            let pool = pool.clone();
+            let thread = thread::spawn(move || {
+                metrics::serve(metrics_listener, &shared.running, &shared.metrics, || Gauges {
+                    connected_clients: shared.clients.lock().unwrap().len(),
+                    queue_depth: pool.as_ref().map_or(0, |pool| pool.queue_depth()),
+                    uptime: shared.start_time.elapsed(),
+                    throttled_requests: shared.throttled_count.load(Ordering::SeqCst),
+                });
+            });
+            metrics_server = Some((wake_address(metrics_addr), thread));
+        }
+
+        // Background thread: print number of clients and queue depth every --log-interval
+        // (10 seconds by default), plus one line per client with --log-client-stats
+        {
+            let shared = Arc::clone(&shared);
+            let pool = pool.clone();
+            let count = shared.clients.lock().unwrap().len();
+            shared.log.info(format_args!("Number of clients connected = {}", count));
+            thread::spawn(move || {
+                log_client_count(&shared, pool.as_deref());
+                while shared.running.load(Ordering::SeqCst) {
+                    let interval = shared.config().log_interval;
+                    thread::sleep(interval);
+                    log_client_count(&shared, pool.as_deref());
+                    if let Some(tokens) = &shared.resume_tokens {
+                        tokens.prune();
+                    }
+                    let log_client_stats = shared.config().log_client_stats;
+                    if log_client_stats {
+                        log_client_stats_lines(&shared);
+                    }
+                }
+            });
+        }
+
+        // Accept loop, followed by the draining shutdown once running goes false
+        let acceptor = {
+            let shared = Arc::clone(&shared);
+            let pool = pool.clone();
+            thread::Builder::new()
+                .name("acceptor".to_string())
+                .spawn(move || accept_and_drain(listener, shared, pool))?
+        };
+
+        Ok(ServerHandle {
+            shared,
+            pool,
+            local_addr,
+            wake_addr: wake_address(local_addr),
+            acceptor: Arc::new(Mutex::new(Some(acceptor))),
+            metrics_wake_addr: metrics_server.as_ref().map(|(addr, _)| *addr),
+            metrics_thread: Arc::new(Mutex::new(metrics_server.map(|(_, thread)| thread))),
+        })
+    }
+}
+
+// A running server. Clones refer to the same server.
+#[derive(Clone)]
+pub struct ServerHandle {
+    shared: Arc<Shared>,
+    pool: Option<Arc<WorkerPool>>,
+    local_addr: SocketAddr,
+    wake_addr: SocketAddr,
+    acceptor: Arc<Mutex<Option<JoinHandle<io::Result<()>>>>>,
+    metrics_wake_addr: Option<SocketAddr>,
+    metrics_thread: Arc<Mutex<Option<JoinHandle<()>>>>,
+}
+
+// Server-wide numbers, as shown by the console's stats command
+#[derive(Debug, Clone)]
+pub struct ServerStats {
+    pub uptime: Duration,
+    pub clients: usize,
+    pub requests: u64,
+    pub throttled: u64,
+    // (busy workers, pool size, queue depth); None in async mode
+    pub pool: Option<(usize, usize, usize)>,
+}
+
+impl ServerHandle {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn port(&self) -> u16 {
+        self.local_addr.port()
+    }
+
+    pub fn logger(&self) -> Arc<Logger> {
+        Arc::clone(&self.shared.log)
+    }
+
+    // False once shutdown has begun
+    pub fn is_running(&self) -> bool {
+        self.shared.running.load(Ordering::SeqCst)
+    }
+
+    // Stop accepting and start draining the clients. Returns false if shutdown
+    // had already begun. Does not wait, see wait().
+    pub fn begin_shutdown(&self) -> bool {
+        if !self.shared.running.swap(false, Ordering::SeqCst) {
+            return false;
+        }
+        // Unblock the accept loops so they see running == false
+        let _ = TcpStream::connect(self.wake_addr);
+        if let Some(addr) = self.metrics_wake_addr {
+            let _ = TcpStream::connect(addr);
+        }
+        true
+    }
+
+    // Block until the server has stopped: the accept loop is done, every client
+    // has finished or was closed at the end of the grace period, and the metrics
+    // port is free again
+    pub fn wait(&self) -> io::Result<()> {
+        let acceptor = self.acceptor.lock().unwrap().take();
+        let result = match acceptor {
+            Some(acceptor) => acceptor.join().unwrap_or_else(|_| Err(io::Error::other("accept thread panicked"))),
+            None => Ok(()),
+        };
+        // The metrics thread only stops once shutdown has begun
+        let metrics_thread = if self.is_running() { None } else { self.metrics_thread.lock().unwrap().take() };
+        if let Some(thread) = metrics_thread {
+            let _ = thread.join();
+        }
+        result
+    }
+
+    pub fn shutdown(&self) -> io::Result<()> {
+        self.begin_shutdown();
+        self.wait()
+    }
+
+    // Statistics of every connected client, sorted by id
+    pub fn clients(&self) -> Vec<(usize, ClientStats)> {
+        let mut clients: Vec<_> = self.shared.clients.lock().unwrap()
+            .iter()
+            .map(|(id, handle)| (*id, handle.stats()))
+            .collect();
+        clients.sort_by_key(|(id, _)| *id);
+        clients
+    }
+
+    // Notify a client and disconnect it. Returns false if there is no such client.
+    pub fn kick(&self, id: usize) -> bool {
+        let handle = self.shared.clients.lock().unwrap().get(&id).cloned();
+        let Some(handle) = handle else {
+            return false;
+        };
+        let addr = handle.stats().addr;
+        handle.kick(&Frame::push(OP_KICK, "you have been disconnected by the server operator"));
+        self.shared.log.log(Level::Info, Fields::client(id, addr),
+            format_args!("Client {} kicked by the operator", id));
+        true
+    }
+
+    // Send a notice to every client. Returns (delivered, clients).
+    pub fn broadcast(&self, text: &str) -> (usize, usize) {
+        let notice = Frame::push(OP_NOTICE, text);
+        // Snapshot the handles first so the clients map is not locked while sending
+        let handles: Vec<ClientHandle> = self.shared.clients.lock().unwrap().values().cloned().collect();
+        // Clients that stopped reading must not hold up the others or the caller:
+        // a notice not through within NOTICE_WAIT counts as not delivered
+        let (sent, results) = mpsc::channel();
+        for handle in &handles {
+            handle.send_in_background(&notice, sent.clone());
+        }
+        drop(sent);
+        let deadline = Instant::now() + NOTICE_WAIT;
+        let mut delivered = 0;
+        while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+            match results.recv_timeout(left) {
+                Ok(true) => delivered += 1,
+                Ok(false) => {}
+                Err(_) => break,
+            }
+        }
+        self.shared.log.info(format_args!("Broadcast notice to {} of {} client(s): {}", delivered, handles.len(), text));
+        (delivered, handles.len())
+    }
+
+    // Apply a configuration that was read again, e.g. on SIGHUP. Connections stay
+    // open; see Config::apply_reload for what changes. The access list file is read
+    // again too. On error nothing changes.
+    pub fn reload(&self, config: Config) -> io::Result<()> {
+        let access = match &config.access_list {
+            Some(path) => AccessList::load(path).inspect_err(|e| {
+                self.shared.log.error(format_args!("Configuration not reloaded: {}", e));
+            })?,
+            None => AccessList::default(),
+        };
+        let access_summary = config.access_list.as_ref()
+            .map(|path| format!(". Access list {}: {} allow, {} deny rule(s)", path.display(), access.allow_rules(), access.deny_rules()));
+        let restart = self.shared.config.write().unwrap().apply_reload(config);
+        *self.shared.access.write().unwrap() = access;
+
+        self.shared.log.info(format_args!("Configuration reloaded{}", access_summary.unwrap_or_default()));
+        for name in restart {
+            self.shared.log.warn(format_args!("Setting '{}' changed, restart the server to apply it", name));
+        }
+        Ok(())
+    }
+
+    pub fn stats(&self) -> ServerStats {
+        ServerStats {
+            uptime: self.shared.start_time.elapsed(),
+            clients: self.shared.clients.lock().unwrap().len(),
+            requests: self.shared.request_count.load(Ordering::SeqCst),
+            throttled: self.shared.throttled_count.load(Ordering::SeqCst),
+            pool: self.pool.as_ref().map(|pool| (pool.busy_workers(), pool.size(), pool.queue_depth())),
+        }
+    }
+}
+
+// Runs on the acceptor thread until shutdown, then drains the clients
+fn accept_and_drain(listener: TcpListener, shared: Arc<Shared>, pool: Option<Arc<WorkerPool>>) -> io::Result<()> {
+    #[cfg(feature = "async")]
+    if shared.config().async_io {
+        return crate::async_server::run(listener, &shared);
+    }
+    let pool = pool.expect("worker pool exists unless --async");
+
+    for stream in listener.incoming() {
+        if !shared.running.load(Ordering::SeqCst) {
+            break;
+        }
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                shared.metrics.record_accept_error();
+                shared.log.error(format_args!("Accept failed: {}", e));
+                continue;
+            }
+        };
+        if let Ok(addr) = stream.peer_addr()
+            && !admit(&shared, addr)
+        {
+            continue;
+        }
+        if let Some(why) = over_capacity(&shared) {
+            reject_busy(&shared, stream, &why);
+            continue;
+        }
+        // Keep a handle to refuse the client if the pool turns the job down
+        let reject_handle = match stream.try_clone() {
+            Ok(handle) => handle,
+            Err(e) => {
+                // Only this connection is lost; the listener keeps going
+                shared.log.error(format_args!("Cannot clone the stream of a new connection: {}", e));
+                continue;
+            }
+        };
+
+        let job_shared = Arc::clone(&shared);
+        if pool.try_execute(Box::new(move || handle_client(stream, job_shared))).is_err() {
+            let why = format!("{} workers busy, {} queued", pool.busy_workers(), pool.queue_depth());
+            reject_busy(&shared, reject_handle, &why);
+        }
+    }
+
+    drop(listener);
+    let grace_period = shared.config().grace_period;
+    shutdown(&shared, || pool.busy_workers() == 0 && pool.queue_depth() == 0, grace_period);
+    Ok(())
+}
+
+// Whether the access list lets a new client in. Rejected peers are logged here and
+// get no client id; the caller just drops the connection.
+pub(crate) fn admit(shared: &Shared, addr: SocketAddr) -> bool {
+    if shared.access.read().unwrap().permits(addr.ip()) {
+        return true;
+    }
+    shared.metrics.record_rejected();
+    shared.log.log(Level::Warn, Fields::peer(addr),
+        format_args!("Rejected connection from {}: not allowed by the access list", addr));
+    false
+}
+
+// Why a new client is turned away with OP_BUSY, if --max-clients are connected already
+pub(crate) fn over_capacity(shared: &Shared) -> Option<String> {
+    let max = shared.config().max_clients?;
+    let connected = shared.clients.lock().unwrap().len();
+    (connected >= max).then(|| format!("{} of {} clients connected", connected, max))
+}
+
+// Address begin_shutdown connects to in order to wake up a blocked accept()
+fn wake_address(local: SocketAddr) -> SocketAddr {
+    let mut addr = local;
+    if addr.ip().is_unspecified() {
+        match addr {
+            SocketAddr::V4(_) => addr.set_ip(std::net::Ipv4Addr::LOCALHOST.into()),
+            SocketAddr::V6(_) => addr.set_ip(std::net::Ipv6Addr::LOCALHOST.into()),
+        }
+    }
+    addr
+}
+
+// Draining shutdown, run after the accept loop has stopped:
+// notify every client, let the workers finish their current request,
+// and force-close whoever is still connected once the grace period is over.
+// `idle` tells when no connection is being served any more.
+pub(crate) fn shutdown(shared: &Shared, idle: impl Fn() -> bool, grace_period: Duration) {
+    let notice = Frame::push(OP_SHUTDOWN, "server shutting down");
+    let handles: Vec<(usize, ClientHandle)> = shared.clients.lock().unwrap()
+        .iter()
+        .map(|(id, handle)| (*id, handle.clone()))
+        .collect();
+    shared.log.info(format_args!("Notifying {} client(s). Grace period {:.1}s",
+        handles.len(), grace_period.as_secs_f64()));
+    // A client that stopped reading holds its notice up for WRITE_TIMEOUT, so the
+    // notices go out on their own thread while the grace period runs
+    let notifier = thread::spawn(move || {
+        for (_id, handle) in &handles {
+            let _ = handle.send(&notice);
+            handle.shutdown_read();
+        }
+    });
+
+    let deadline = Instant::now() + grace_period;
+    while Instant::now() < deadline {
+        if idle() {
+            shared.log.info("All client handlers finished.");
+            let _ = notifier.join();
+            return;
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    let remaining: Vec<(usize, ClientHandle)> = shared.clients.lock().unwrap().drain().collect();
+    shared.log.warn(format_args!("Grace period over. Closing {} remaining connection(s).", remaining.len()));
+    for (_id, handle) in remaining {
+        handle.close();
+    }
+    // A notice still stuck fails once its connection is closed, or at WRITE_TIMEOUT
+    let _ = notifier.join();
+}
+
+// The queue depth is only there with the worker pool
+fn log_client_count(shared: &Shared, pool: Option<&WorkerPool>) {
+    let count = shared.clients.lock().unwrap().len();
+    let throttled = shared.throttled_count.load(Ordering::SeqCst);
+    match pool {
+        Some(pool) => shared.log.info(format_args!("Number of clients connected = {}. Queue depth = {}. Throttled requests = {}",
+            count, pool.queue_depth(), throttled)),
+        None => shared.log.info(format_args!("Number of clients connected = {}. Throttled requests = {}", count, throttled)),
+    }
+}
+
+fn log_client_stats_lines(shared: &Shared) {
+    let mut stats: Vec<_> = shared.clients.lock().unwrap()
+        .iter()
+        .map(|(id, handle)| (*id, handle.stats()))
+        .collect();
+    stats.sort_by_key(|(id, _)| *id);
+    for (id, client) in stats {
+        shared.log.log(Level::Info, Fields::client(id, client.addr), client.summary(id));
+    }
+}
+
+// Tell the client the server is saturated and close the connection. The accept loop
+// does not run TLS handshakes, so a TLS client just sees the connection close.
+fn reject_busy(shared: &Shared, mut stream: TcpStream, why: &str) {
+    let message = |peer: &dyn std::fmt::Display| format!("Server busy ({}). Rejected connection from {}", why, peer);
+    match stream.peer_addr() {
+        Ok(peer) => shared.log.log(Level::Warn, Fields::peer(peer), message(&peer)),
+        Err(_) => shared.log.warn(message(&"unknown")),
+    }
+    if shared.tls.is_none() {
+        let _ = write_frame(&mut stream, &Frame::push(OP_BUSY, "server busy, try again later"));
+    }
+    let _ = stream.shutdown(Shutdown::Both);
+}
+
+// A client the server is talking to, over TCP or UDP. Shared by the requests of
+// the client that are in progress at the same time.
+pub(crate) struct Session {
+    pub(crate) client_id: usize,
+    pub(crate) addr: SocketAddr,
+    pub(crate) handle: ClientHandle,
+    // None without a rate limit; rebuilt when a reload changes the limit
+    bucket: Mutex<Option<TokenBucket>>,
+    // Resume token, TCP only
+    token: Option<String>,
+    // Set when the client ended the session itself (OPT5)
+    left: AtomicBool,
+}
+
+impl Session {
+    // Take a token from the rate limit bucket; false if the request is throttled.
+    // The bucket follows the current limit, which a reload may have changed.
+    fn take_token(&self, limit: Option<RateLimit>) -> bool {
+        let mut bucket = self.bucket.lock().unwrap();
+        if bucket.as_ref().map(TokenBucket::limit) != limit {
+            *bucket = limit.map(TokenBucket::new);
+        }
+        bucket.as_mut().is_none_or(TokenBucket::try_acquire)
+    }
+}
+
+// Whether the client wants to keep going after a request
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Flow {
+    Continue,
+    Close,
+}
+
+// Give the client an id, or its previous one when it resumes a parked session,
+// add it to the clients map and log the connect line
+pub(crate) fn connect_client(
+    shared: &Shared,
+    handle: ClientHandle,
+    addr: SocketAddr,
+    label: &str,
+    resumed: Option<Parked>,
+) -> Session {
+    let (client_id, verb) = match &resumed {
+        Some(parked) => {
+            handle.restore(&parked.stats);
+            (parked.client_id, "resumed")
+        }
+        None => (shared.client_id_counter.fetch_add(1, Ordering::SeqCst), "connected"),
+    };
+
+    let mut clients_lock = shared.clients.lock().unwrap();
+    clients_lock.insert(client_id, handle.clone());
+    shared.log.log(Level::Info, Fields::client(client_id, addr),
+        format_args!("Client {} {}{}. Number of clients connected = {}", client_id, verb, label, clients_lock.len()));
+    drop(clients_lock);
+
+    Session {
+        client_id,
+        addr,
+        handle,
+        bucket: Mutex::new(shared.config().rate_limit.map(TokenBucket::new)),
+        token: None,
+        left: AtomicBool::new(false),
+    }
+}
+
+// Start the session of a new TCP connection. A client that wants its old session back
+// sends OP_RESUME as its first frame; any other first frame is handed back to be served.
+// The client gets the session's resume token either way.
+pub(crate) fn start_tcp_session(
+    shared: &Shared,
+    handle: ClientHandle,
+    addr: SocketAddr,
+    first: Option<Frame>,
+) -> (Session, Option<Frame>) {
+    if shared.resume_tokens.is_none() {
+        return (connect_client(shared, handle, addr, "", None), first);
+    }
+
+    let (mut session, pending, resume_reply) = match first {
+        Some(request) if request.opcode == OP_RESUME => {
+            let token = request.text();
+            let parked = shared.resume_tokens.as_ref().and_then(|tokens| tokens.take(&token));
+            let resumed = parked.is_some();
+            let mut session = connect_client(shared, handle, addr, "", parked);
+            let reply = if resumed {
+                session.token = Some(token);
+                Frame::new(OP_REPLY, format!("resumed as client {}", session.client_id))
+            } else {
+                Frame::new(OP_ERROR, "unknown or expired resume token")
+            };
+            (session, None, Some(reply.with_id(request.id)))
+        }
+        other => (connect_client(shared, handle, addr, "", None), other, None),
+    };
+
+    let token = session.token.get_or_insert_with(ResumeTokens::new_token).clone();
+    let _ = session.handle.send(&Frame::push(OP_SESSION, token));
+    if let Some(reply) = resume_reply {
+        let _ = session.handle.send(&reply);
+    }
+    (session, pending)
+}
+
+// End a TCP session: log the disconnect, then park it for resumption unless it
+// ended on purpose. Parking comes last, so a client that resumes right away is not
+// removed from the clients map by its old connection.
+pub(crate) fn end_tcp_session(shared: &Shared, session: &Session, reason: Option<&str>) {
+    disconnect_client(shared, session, reason);
+    if let (Some(tokens), Some(token)) = (&shared.resume_tokens, &session.token)
+        && shared.running.load(Ordering::SeqCst)
+        && !session.left.load(Ordering::SeqCst)
+        && !session.handle.is_kicked()
+    {
+        tokens.park(token, session.client_id, session.handle.stats());
+    }
+}
+
+// Remove the client from the clients map and log the disconnect line, with a reason
+// if it did not simply leave
+pub(crate) fn disconnect_client(shared: &Shared, session: &Session, reason: Option<&str>) {
+    let mut clients_lock = shared.clients.lock().unwrap();
+    clients_lock.remove(&session.client_id);
+    let fields = Fields::client(session.client_id, session.addr);
+    match reason {
+        Some(reason) => shared.log.log(Level::Info, fields,
+            format_args!("Client {} disconnected ({}). Number of clients connected = {}", session.client_id, reason, clients_lock.len())),
+        None => shared.log.log(Level::Info, fields,
+            format_args!("Client {} disconnected. Number of clients connected = {}", session.client_id, clients_lock.len())),
+    }
+}
+
+// Everything between reading a request and writing its reply, the same for TCP and UDP:
+// logging, statistics, rate limiting, dispatch and metrics. `send` writes the reply,
+// which carries the id of the request.
+pub(crate) fn serve_request(
+    shared: &Shared,
+    session: &Session,
+    request: &Frame,
+    send: impl FnOnce(&Frame) -> io::Result<()>,
+) -> io::Result<Flow> {
+    let (client_id, client_addr) = (session.client_id, session.addr);
+    if shared.log.enabled(Level::Debug) {
+        // Payload text only goes to the log with --log-payloads
+        let text = if shared.log.log_payloads() { request.text() } else { String::new() };
+        shared.log.log(Level::Debug, Fields::client(client_id, client_addr).opcode(request.opcode).payload(&text),
+            format_args!("Received {} ({} bytes) from client {}", opcode_name(request.opcode), request.payload.len(), client_id));
+    }
+    session.handle.record_request(request);
+
+    // Over the limit: refuse without running the command or counting it as served.
+    // OPT5 is never throttled so a client can always leave.
+    if request.opcode != OP_QUIT && !session.take_token(shared.config().rate_limit) {
+        shared.throttled_count.fetch_add(1, Ordering::SeqCst);
+        session.handle.record_throttled();
+        let reply = Frame::new(OP_ERROR, "rate limit exceeded, slow down").with_id(request.id);
+        send(&reply)?;
+        session.handle.record_sent(&reply);
+        return Ok(Flow::Continue);
+    }
+
+    let received_at = Instant::now();
+    // The handshake is not a command, OPT4 does not count it
+    if request.opcode != OP_HELLO {
+        shared.request_count.fetch_add(1, Ordering::SeqCst);
+    }
+    shared.metrics.record_request(request.opcode);
+
+    let ctx = Context {
+        client_id,
+        peer_addr: client_addr,
+        start_time: shared.start_time,
+        request_count: &shared.request_count,
+        clients: &shared.clients,
+    };
+    let reply = match shared.registry.dispatch(&ctx, request) {
+        Outcome::Reply(frame) => frame.with_id(request.id),
+        Outcome::Close => {
+            session.left.store(true, Ordering::SeqCst);
+            return Ok(Flow::Close);
+        }
+    };
+
+    send(&reply)?;
+    session.handle.record_sent(&reply);
+    shared.metrics.observe_latency(request.opcode, received_at.elapsed());
+    Ok(Flow::Continue)
+}
+
+// Read side of a TCP connection, plain or TLS
+enum Incoming {
+    Plain(TcpStream),
+    Tls(TlsReader),
+}
+
+impl Incoming {
+    fn socket(&self) -> &TcpStream {
+        match self {
+            Incoming::Plain(stream) => stream,
+            Incoming::Tls(reader) => reader.socket(),
+        }
+    }
+
+    // Whether data arrived (within the read timeout) without consuming it
+    fn data_ready(&mut self) -> io::Result<bool> {
+        match self {
+            Incoming::Plain(stream) => stream.peek(&mut [0u8; 1]).map(|n| n > 0),
+            Incoming::Tls(reader) => reader.fill(),
+        }
+    }
+}
+
+impl Read for Incoming {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Incoming::Plain(stream) => stream.read(buf),
+            Incoming::Tls(reader) => reader.read(buf),
+        }
+    }
+}
+
+// The handle and read side of an accepted connection, after the TLS handshake if
+// the server uses TLS
+fn open_connection(stream: TcpStream, addr: SocketAddr, shared: &Shared) -> io::Result<(ClientHandle, Incoming)> {
+    let Some(settings) = &shared.tls else {
+        return Ok((ClientHandle::new(&stream, addr)?, Incoming::Plain(stream)));
+    };
+    let connection = TlsConnection::accept(settings, stream).inspect_err(|e| {
+        shared.log.log(Level::Warn, Fields::peer(addr), format_args!("TLS handshake with {} failed: {}", addr, e));
+    })?;
+    Ok((ClientHandle::tls(Arc::clone(&connection), addr), Incoming::Tls(connection.reader()?)))
+}
+
+// Connection lifetime of one TCP client, runs on a pool worker
+fn handle_client(mut stream: TcpStream, shared: Arc<Shared>) {
+    let client_addr = match stream.peer_addr() {
+        Ok(addr) => addr,
+        Err(_) => return, // closed while waiting in the queue
+    };
+    if !shared.running.load(Ordering::SeqCst) {
+        // Picked up from the queue after shutdown started. A TLS client could not
+        // read a plaintext notice, it just sees the connection close.
+        if shared.tls.is_none() {
+            let _ = write_frame(&mut stream, &Frame::push(OP_SHUTDOWN, "server shutting down"));
+        }
+        let _ = stream.shutdown(Shutdown::Both);
+        return;
+    }
+    let (handle, mut incoming) = match open_connection(stream, client_addr, &shared) {
+        Ok(opened) => opened,
+        Err(_) => return,
+    };
+    if let Err(e) = configure_socket(incoming.socket(), &shared.config()) {
+        shared.log.log(Level::Warn, Fields::peer(client_addr),
+            format_args!("Could not apply socket options for {}: {}", client_addr, e));
+    }
+
+    let first = match read_first_frame(&mut incoming, &shared) {
+        Ok(first) => first,
+        Err(_) => return,
+    };
+    let (session, mut pending) = start_tcp_session(&shared, handle, client_addr, first);
+    let session = Arc::new(session);
+    let mut idle_timeout = shared.config().idle_timeout;
+    // Requests handed out and the outcomes that came back
+    let (done_sender, done) = mpsc::channel();
+    let mut in_flight = 0;
+
+    // Set when the connection ends for a reason other than the client leaving
+    let mut reason: Option<String> = None;
+    loop {
+        // Collect requests that are done, stop on the first that failed
+        for result in done.try_iter() {
+            in_flight -= 1;
+            reason = reason.or(failure(result));
+        }
+        if reason.is_some() {
+            break;
+        }
+        if !shared.running.load(Ordering::SeqCst) {
+            break;
+        }
+        // A reload may have changed the idle timeout since the last request
+        let current = shared.config().idle_timeout;
+        if current != idle_timeout {
+            let _ = incoming.socket().set_read_timeout(current);
+            idle_timeout = current;
+        }
+        // One frame is one request, however the bytes were split into segments
+        let read = match pending.take() {
+            Some(frame) => Ok(Some(frame)),
+            None => read_frame(&mut incoming),
+        };
+        let request = match read {
+            Ok(Some(frame)) => frame,
+            Ok(None) => break,
+            // The read timeout is the idle timeout
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {
+                reason = Some("idle timeout".to_string());
+                break;
+            }
+            Err(e) => {
+                reason = Some(format!("read error: {}", e));
+                break;
+            }
+        };
+
+        let job = {
+            let shared = Arc::clone(&shared);
+            let session = Arc::clone(&session);
+            let done_sender = done_sender.clone();
+            Box::new(move || {
+                let handle = session.handle.clone();
+                let result = serve_request(&shared, &session, &request, |reply| handle.write(reply));
+                if let Ok(Flow::Close) = result {
+                    // OPT5: the read loop sees EOF, replies still in progress go out
+                    session.handle.shutdown_read();
+                }
+                let _ = done_sender.send(result);
+            })
+        };
+        in_flight += 1;
+        // Replies may overtake each other, clients match them by id
+        let requests = shared.requests.as_ref().expect("request pool exists unless --async");
+        if in_flight > MAX_IN_FLIGHT {
+            job();
+        } else if let Err(job) = requests.try_execute(job) {
+            job();
+        }
+    }
+
+    // Requests still in progress finish before the socket is closed; a job that
+    // panicked drops its sender, so this cannot wait forever
+    drop(done_sender);
+    while in_flight > 0 {
+        let Ok(result) = done.recv() else { break };
+        in_flight -= 1;
+        reason = reason.or(failure(result));
+    }
+
+    if session.handle.is_kicked() {
+        // The operator closed the socket under us, whatever the read loop saw
+        reason = Some("kicked by operator".to_string());
+    }
+    if reason.is_some() {
+        // Make sure the client notices, e.g. after an idle timeout
+        session.handle.close();
+    }
+    end_tcp_session(&shared, &session, reason.as_deref());
+}
+
+// Why a finished request ends the connection, if it does
+fn failure(result: io::Result<Flow>) -> Option<String> {
+    result.err().map(|e| format!("write error: {}", e))
+}
+
+// The first frame of a connection, if it arrives within RESUME_WAIT
+fn read_first_frame(incoming: &mut Incoming, shared: &Shared) -> io::Result<Option<Frame>> {
+    if shared.resume_tokens.is_none() {
+        return Ok(None);
+    }
+    incoming.socket().set_read_timeout(Some(RESUME_WAIT))?;
+    let arrived = match incoming.data_ready() {
+        Ok(arrived) => arrived,
+        Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => false,
+        Err(e) => return Err(e),
+    };
+    // Back to the idle timeout
+    let idle_timeout = shared.config().idle_timeout;
+    incoming.socket().set_read_timeout(idle_timeout)?;
+    if arrived { read_frame(incoming) } else { Ok(None) }
+}
+
+// Idle timeout (as read timeout), no Nagle delay and TCP keepalive for an accepted
+// connection. Replies to pipelined requests are written one by one, and Nagle's
+// algorithm would hold each back until the client acknowledged the one before.
+fn configure_socket(stream: &TcpStream, config: &Config) -> io::Result<()> {
+    stream.set_read_timeout(config.idle_timeout)?;
+    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    stream.set_nodelay(true)?;
+    if let Some(idle) = config.keepalive {
+        let keepalive = TcpKeepalive::new()
+            .with_time(idle)
+            .with_interval(config.keepalive_interval);
+        SockRef::from(stream).set_tcp_keepalive(&keepalive)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,248 @@
+// TLS for TCP clients (rustls)
+//
+// With --tls-cert and --tls-key every TCP connection starts with a TLS handshake,
+// and the OPT frames travel inside the encrypted stream unchanged. With
+// --tls-client-ca a client also has to present a certificate signed by one of
+// those CAs, or the handshake fails. UDP is not encrypted, so the server refuses to
+// combine TLS with --udp.
+//
+// A TlsConnection is shared like a TcpStream clone: the connection thread reads
+// through a TlsReader, which waits on the socket without holding the rustls lock,
+// while replies and notices from any thread go through write_all.
+
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig, ServerConnection};
+
+// How long a new connection may take to complete the TLS handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Certificate, key and client verification of a TLS server; cheap to clone
+#[derive(Clone)]
+pub struct TlsSettings {
+    config: Arc<ServerConfig>,
+    client_auth: bool,
+}
+
+impl TlsSettings {
+    // Settings from PEM files, as given by --tls-cert, --tls-key and --tls-client-ca
+    pub fn from_files(cert: &Path, key: &Path, client_ca: Option<&Path>) -> io::Result<Self> {
+        let read = |path: &Path| {
+            std::fs::read(path).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+        };
+        let client_ca = client_ca.map(read).transpose()?;
+        TlsSettings::from_pem(&read(cert)?, &read(key)?, client_ca.as_deref())
+    }
+
+    // Settings from PEM text: the certificate chain, its private key and optionally
+    // the CA certificates that client certificates must be signed by
+    pub fn from_pem(cert: &[u8], key: &[u8], client_ca: Option<&[u8]>) -> io::Result<Self> {
+        let certs = CertificateDer::pem_slice_iter(cert)
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| invalid(format!("invalid certificate: {}", e)))?;
+        if certs.is_empty() {
+            return Err(invalid("no certificate found".to_string()));
+        }
+        let key = PrivateKeyDer::from_pem_slice(key).map_err(|e| invalid(format!("invalid private key: {}", e)))?;
+
+        let builder = ServerConfig::builder();
+        let builder = match client_ca {
+            Some(client_ca) => {
+                let mut roots = RootCertStore::empty();
+                for ca in CertificateDer::pem_slice_iter(client_ca) {
+                    let ca = ca.map_err(|e| invalid(format!("invalid client CA certificate: {}", e)))?;
+                    roots.add(ca).map_err(|e| invalid(format!("invalid client CA certificate: {}", e)))?;
+                }
+                let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+                    .build()
+                    .map_err(|e| invalid(format!("client CA: {}", e)))?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let config = builder
+            .with_single_cert(certs, key)
+            .map_err(|e| invalid(format!("certificate and key do not work together: {}", e)))?;
+        Ok(TlsSettings { config: Arc::new(config), client_auth: client_ca.is_some() })
+    }
+
+    // A fresh self-signed certificate for these host names. Returns the settings
+    // and the certificate in PEM form for the client to trust.
+    #[cfg(test)]
+    pub(crate) fn self_signed(names: &[&str]) -> io::Result<(Self, String)> {
+        let names: Vec<String> = names.iter().map(|name| name.to_string()).collect();
+        let generated = rcgen::generate_simple_self_signed(names).map_err(|e| invalid(e.to_string()))?;
+        let cert = generated.cert.pem();
+        let settings = TlsSettings::from_pem(cert.as_bytes(), generated.key_pair.serialize_pem().as_bytes(), None)?;
+        Ok((settings, cert))
+    }
+
+    // Whether clients have to present a certificate
+    pub fn client_auth(&self) -> bool {
+        self.client_auth
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message)
+}
+
+// A TLS connection to one client, after the handshake
+pub(crate) struct TlsConnection {
+    conn: Mutex<ServerConnection>,
+    socket: TcpStream,
+}
+
+impl TlsConnection {
+    // Run the handshake on an accepted socket. The socket's read timeout is left at
+    // HANDSHAKE_TIMEOUT; the caller sets the one it wants afterwards.
+    pub(crate) fn accept(settings: &TlsSettings, mut socket: TcpStream) -> io::Result<Arc<Self>> {
+        let mut conn = ServerConnection::new(Arc::clone(&settings.config)).map_err(io::Error::other)?;
+        socket.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+        while conn.is_handshaking() {
+            conn.complete_io(&mut socket)?;
+        }
+        Ok(Arc::new(TlsConnection { conn: Mutex::new(conn), socket }))
+    }
+
+    pub(crate) fn socket(&self) -> &TcpStream {
+        &self.socket
+    }
+
+    // Encrypt and send, in one piece with respect to other writers. rustls buffers a
+    // limited amount of plaintext, so large frames go out in several rounds. A failed
+    // write closes the socket, see ClientHandle::write.
+    pub(crate) fn write_all(&self, mut bytes: &[u8]) -> io::Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        while !bytes.is_empty() {
+            let written = conn.writer().write(bytes).and_then(|n| flush(&mut conn, &self.socket).map(|_| n));
+            match written {
+                Ok(n) => bytes = &bytes[n..],
+                Err(e) => {
+                    let _ = self.socket.shutdown(Shutdown::Both);
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Send close_notify and close the socket. While another thread holds the lock,
+    // e.g. a write to a client that stopped reading, the socket is closed without
+    // close_notify rather than waiting for it.
+    pub(crate) fn close(&self) {
+        if let Ok(mut conn) = self.conn.try_lock() {
+            conn.send_close_notify();
+            let _ = flush(&mut conn, &self.socket);
+        }
+        let _ = self.socket.shutdown(Shutdown::Both);
+    }
+
+    // The read side for the connection thread
+    pub(crate) fn reader(self: &Arc<Self>) -> io::Result<TlsReader> {
+        Ok(TlsReader { connection: Arc::clone(self), socket: self.socket.try_clone()?, raw: vec![0; 16 * 1024], start: 0, end: 0 })
+    }
+}
+
+// Write out whatever rustls has queued: records, alerts, close_notify
+fn flush(conn: &mut MutexGuard<'_, ServerConnection>, mut socket: &TcpStream) -> io::Result<()> {
+    while conn.wants_write() {
+        conn.write_tls(&mut socket)?;
+    }
+    Ok(())
+}
+
+// Plaintext from a TlsConnection. Raw bytes are read from the socket without the
+// lock, so writers are never held up by a reader waiting for the client.
+pub(crate) struct TlsReader {
+    connection: Arc<TlsConnection>,
+    socket: TcpStream,
+    // Received bytes rustls has not taken yet
+    raw: Vec<u8>,
+    start: usize,
+    end: usize,
+}
+
+impl TlsReader {
+    pub(crate) fn socket(&self) -> &TcpStream {
+        &self.socket
+    }
+
+    // Wait until plaintext is available; false when the client closed the connection.
+    // Blocks for at most the socket's read timeout per read.
+    pub(crate) fn fill(&mut self) -> io::Result<bool> {
+        loop {
+            let mut conn = self.connection.conn.lock().unwrap();
+            let state = conn.process_new_packets();
+            // Alerts and handshake messages have to go out even if processing failed
+            let flushed = flush(&mut conn, &self.socket);
+            let state = state.map_err(|e| invalid(e.to_string()))?;
+            flushed?;
+            if state.plaintext_bytes_to_read() > 0 {
+                return Ok(true);
+            }
+            if state.peer_has_closed() {
+                return Ok(false);
+            }
+            if self.start < self.end {
+                let mut raw = &self.raw[self.start..self.end];
+                self.start += conn.read_tls(&mut raw)?;
+                continue;
+            }
+            drop(conn);
+
+            let n = (&self.socket).read(&mut self.raw)?;
+            if n == 0 {
+                return Ok(false);
+            }
+            (self.start, self.end) = (0, n);
+        }
+    }
+}
+
+impl Read for TlsReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || !self.fill()? {
+            return Ok(0);
+        }
+        self.connection.conn.lock().unwrap().reader().read(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn self_signed_settings() {
+        let (settings, cert) = TlsSettings::self_signed(&["localhost"]).unwrap();
+        assert!(!settings.client_auth());
+        assert!(cert.starts_with("-----BEGIN CERTIFICATE-----"));
+    }
+
+    #[test]
+    fn client_ca_turns_on_client_auth() {
+        let generated = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let (cert, key) = (generated.cert.pem(), generated.key_pair.serialize_pem());
+        let settings = TlsSettings::from_pem(cert.as_bytes(), key.as_bytes(), Some(cert.as_bytes())).unwrap();
+        assert!(settings.client_auth());
+    }
+
+    #[test]
+    fn bad_pem_is_rejected() {
+        let generated = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let (cert, key) = (generated.cert.pem(), generated.key_pair.serialize_pem());
+        let error = |result: io::Result<TlsSettings>| result.err().map(|e| e.to_string()).unwrap_or_default();
+        assert_eq!(error(TlsSettings::from_pem(b"", key.as_bytes(), None)), "no certificate found");
+        assert!(error(TlsSettings::from_pem(cert.as_bytes(), b"", None)).starts_with("invalid private key"));
+        let other = rcgen::KeyPair::generate().unwrap().serialize_pem();
+        assert!(error(TlsSettings::from_pem(cert.as_bytes(), other.as_bytes(), None)).starts_with("certificate and key"));
+    }
+}
@@ -0,0 +1,149 @@
+// UDP variant of the OPT service
+//
+// The command set is the same as over TCP. A client is identified by its source
+// address: its session (client id, statistics, rate limit) starts with the first
+// datagram and ends when it sends OPT5 or stays silent for --udp-session-timeout.
+// Clients retransmit when a reply is lost, so each session keeps its last reply and
+// sends it again for a repeated request id instead of running the command twice.
+// Sessions kicked from the admin console are dropped on the next pass of the loop.
+
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::clients::ClientHandle;
+use crate::logger::{Fields, Level};
+use crate::protocol::{decode_datagram, encode_datagram, Frame, OP_BUSY, OP_ERROR};
+use crate::server::{admit, connect_client, over_capacity, disconnect_client, serve_request, Flow, Session, Shared};
+
+// How often the loop wakes up to expire sessions and check for shutdown
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+struct UdpSession {
+    session: Session,
+    last_seen: Instant,
+    // Request id and encoded datagram of the last reply, for retransmitted requests
+    last_reply: Option<(u32, Vec<u8>)>,
+}
+
+pub fn serve(socket: UdpSocket, shared: Arc<Shared>) {
+    let socket = Arc::new(socket);
+    if let Err(e) = socket.set_read_timeout(Some(POLL_INTERVAL)) {
+        shared.log.error(format_args!("UDP: cannot set read timeout: {}", e));
+        return;
+    }
+    let mut sessions: HashMap<SocketAddr, UdpSession> = HashMap::new();
+    let mut buf = vec![0u8; 65536];
+
+    while shared.running.load(Ordering::SeqCst) {
+        match socket.recv_from(&mut buf) {
+            Ok((n, addr)) => handle_datagram(&shared, &socket, &mut sessions, &buf[..n], addr),
+            Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {}
+            // An ICMP error for an earlier reply (client gone) shows up here on some platforms
+            Err(e) if e.kind() == ErrorKind::ConnectionReset => {}
+            Err(e) => shared.log.warn(format_args!("UDP: receive failed: {}", e)),
+        }
+        expire_sessions(&shared, &mut sessions);
+    }
+}
+
+fn handle_datagram(
+    shared: &Shared,
+    socket: &Arc<UdpSocket>,
+    sessions: &mut HashMap<SocketAddr, UdpSession>,
+    datagram: &[u8],
+    addr: SocketAddr,
+) {
+    let request = match decode_datagram(datagram) {
+        Ok(decoded) => decoded,
+        Err(e) => {
+            shared.log.log(Level::Debug, Fields::peer(addr), format_args!("UDP: dropped malformed datagram from {}: {}", addr, e));
+            return;
+        }
+    };
+
+    // A kicked client that keeps sending starts over with a new session
+    if sessions.get(&addr).is_some_and(|s| s.session.handle.is_kicked())
+        && let Some(udp_session) = sessions.remove(&addr)
+    {
+        disconnect_client(shared, &udp_session.session, Some("kicked by operator"));
+    }
+
+    // Only a datagram that would start a session is checked against the access list
+    // and --max-clients
+    if !sessions.contains_key(&addr) {
+        if !admit(shared, addr) {
+            return;
+        }
+        if let Some(why) = over_capacity(shared) {
+            shared.log.log(Level::Warn, Fields::peer(addr),
+                format_args!("Server busy ({}). Rejected UDP client {}", why, addr));
+            if let Ok(busy) = encode_datagram(&Frame::new(OP_BUSY, "server busy, try again later").with_id(request.id)) {
+                let _ = socket.send_to(&busy, addr);
+            }
+            return;
+        }
+    }
+
+    let udp_session = sessions.entry(addr).or_insert_with(|| UdpSession {
+        session: connect_client(shared, ClientHandle::udp(Arc::clone(socket), addr), addr, " (udp)", None),
+        last_seen: Instant::now(),
+        last_reply: None,
+    });
+    udp_session.last_seen = Instant::now();
+
+    // Retransmission of a request we already answered: repeat the answer
+    if let Some((last_id, reply)) = &udp_session.last_reply
+        && *last_id == request.id
+    {
+        let _ = socket.send_to(reply, addr);
+        return;
+    }
+
+    let mut sent = None;
+    let result = serve_request(shared, &udp_session.session, &request, |reply| {
+        let datagram = encode_datagram(reply)
+            .or_else(|_| encode_datagram(&Frame::new(OP_ERROR, "reply too large for a UDP datagram").with_id(request.id)))?;
+        socket.send_to(&datagram, addr)?;
+        sent = Some(datagram);
+        Ok(())
+    });
+    if let Some(datagram) = sent {
+        udp_session.last_reply = Some((request.id, datagram));
+    }
+
+    match result {
+        Ok(Flow::Continue) => {}
+        Ok(Flow::Close) => {
+            if let Some(udp_session) = sessions.remove(&addr) {
+                disconnect_client(shared, &udp_session.session, None);
+            }
+        }
+        Err(e) => shared.log.log(Level::Warn, Fields::peer(addr), format_args!("UDP: send to {} failed: {}", addr, e)),
+    }
+}
+
+// Drop sessions that have been silent too long or were kicked
+fn expire_sessions(shared: &Shared, sessions: &mut HashMap<SocketAddr, UdpSession>) {
+    let timeout = shared.config().udp_session_timeout;
+    let expired: Vec<(SocketAddr, &'static str)> = sessions
+        .iter()
+        .filter_map(|(addr, s)| {
+            if s.session.handle.is_kicked() {
+                Some((*addr, "kicked by operator"))
+            } else if s.last_seen.elapsed() >= timeout {
+                Some((*addr, "session expired"))
+            } else {
+                None
+            }
+        })
+        .collect();
+    for (addr, reason) in expired {
+        if let Some(udp_session) = sessions.remove(&addr) {
+            disconnect_client(shared, &udp_session.session, Some(reason));
+        }
+    }
+}
@@ -0,0 +1,42 @@
+// The metrics endpoint stops with the server, so a second server in the same
+// process can take over its port
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+
+use multi_tcp_server::config::Config;
+use multi_tcp_server::logger::Level;
+use multi_tcp_server::{Server, ServerHandle};
+
+fn start_server(metrics_port: u16) -> ServerHandle {
+    let config = Config { log_level: Level::Warn, metrics_port: Some(metrics_port), ..Config::default() };
+    Server::builder().config(config).bind("127.0.0.1:0".parse().unwrap()).start().unwrap()
+}
+
+fn scrape(addr: SocketAddr) -> String {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+// A port that was free a moment ago
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+#[test]
+fn metrics_port_is_released_on_shutdown() {
+    let port = free_port();
+    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+
+    let server = start_server(port);
+    assert!(scrape(addr).starts_with("HTTP/1.1 200 OK"));
+    server.shutdown().unwrap();
+    assert!(TcpStream::connect(addr).is_err(), "metrics still served after shutdown");
+
+    let server = start_server(port);
+    assert!(scrape(addr).starts_with("HTTP/1.1 200 OK"));
+    server.shutdown().unwrap();
+}
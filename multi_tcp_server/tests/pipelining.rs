@@ -0,0 +1,93 @@
+// Pipelined requests on one connection are served concurrently and every reply
+// carries the id of its request
+
+use std::collections::HashSet;
+use std::io::Write;
+use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use multi_tcp_client::protocol::Frame as ClientFrame;
+use multi_tcp_client::{Error as ClientError, OptClient};
+use multi_tcp_server::commands::{CommandHandler, Context, Outcome};
+use multi_tcp_server::config::Config;
+use multi_tcp_server::logger::Level;
+use multi_tcp_server::protocol::{encode_frame, read_frame, Frame, OP_REPLY};
+use multi_tcp_server::Server;
+
+const OP_SLOW: u8 = 0x10;
+const DELAY: Duration = Duration::from_millis(300);
+
+// Answers after DELAY, with its payload
+struct Slow;
+
+impl CommandHandler for Slow {
+    fn handle(&self, _ctx: &Context, payload: &[u8]) -> Outcome {
+        thread::sleep(DELAY);
+        Outcome::reply(payload)
+    }
+}
+
+#[test]
+fn pipelined_requests_overlap() {
+    let config = Config { log_level: Level::Warn, resume_window: None, ..Config::default() };
+    let server = Server::builder()
+        .config(config)
+        .bind("127.0.0.1:0".parse().unwrap())
+        .workers(4)
+        .handler(OP_SLOW, Slow)
+        .start()
+        .unwrap();
+    let mut stream = TcpStream::connect(server.local_addr()).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+    // Every request goes out before any reply is read
+    let start = Instant::now();
+    let mut bytes = Vec::new();
+    for id in 1..=4u32 {
+        bytes.extend(encode_frame(&Frame::new(OP_SLOW, id.to_string()).with_id(id)).unwrap());
+    }
+    stream.write_all(&bytes).unwrap();
+
+    let mut ids = HashSet::new();
+    while ids.len() < 4 {
+        let reply = read_frame(&mut stream).unwrap().expect("connection closed");
+        assert_eq!(reply.opcode, OP_REPLY);
+        assert_eq!(reply.text(), reply.id.to_string());
+        assert!(ids.insert(reply.id), "reply {} twice", reply.id);
+    }
+    // One after the other they would take four times DELAY
+    assert!(start.elapsed() < DELAY * 3, "took {:?}", start.elapsed());
+
+    drop(stream);
+    server.shutdown().unwrap();
+}
+
+#[test]
+fn client_pipeline_yields_replies_in_order() {
+    let config = Config { log_level: Level::Warn, ..Config::default() };
+    let server = Server::builder()
+        .config(config)
+        .bind("127.0.0.1:0".parse().unwrap())
+        .workers(4)
+        .handler(OP_SLOW, Slow)
+        .start()
+        .unwrap();
+    let mut client = OptClient::connect(server.local_addr()).unwrap();
+
+    let start = Instant::now();
+    let requests = (1..=4).map(|n| ClientFrame::new(OP_SLOW, n.to_string())).collect();
+    let replies: Vec<_> = client.pipeline(requests).unwrap().collect::<Result<_, _>>().unwrap();
+    assert!(start.elapsed() < DELAY * 3, "took {:?}", start.elapsed());
+    let texts: Vec<String> = replies.iter().map(|reply| reply.frame.text()).collect();
+    assert_eq!(texts, ["1", "2", "3", "4"]);
+    assert!(replies.iter().all(|reply| reply.rtt >= DELAY));
+
+    // Not offered by the server: nothing is sent
+    let requests = vec![ClientFrame::new(OP_SLOW, "x"), ClientFrame::new(0x42, "")];
+    assert!(matches!(client.pipeline(requests), Err(ClientError::Unsupported(0x42))));
+    assert_eq!(client.to_upper("still fine").unwrap(), "STILL FINE");
+
+    client.close().unwrap();
+    server.shutdown().unwrap();
+}
@@ -0,0 +1,108 @@
+// Session resumption over a real connection: a client that drops and comes back
+// with its token gets its old id and counters, and stays in the clients map; a
+// kicked client is told so and cannot come back
+
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use multi_tcp_server::config::Config;
+use multi_tcp_server::logger::Level;
+use multi_tcp_server::protocol::{
+    read_frame, write_frame, Frame, OP_COUNT, OP_ERROR, OP_KICK, OP_QUIT, OP_REPLY, OP_RESUME, OP_SESSION, OP_UPPER,
+};
+use multi_tcp_server::{Server, ServerHandle};
+
+fn start_server() -> ServerHandle {
+    let config = Config { log_level: Level::Warn, ..Config::default() };
+    Server::builder().config(config).bind("127.0.0.1:0".parse().unwrap()).start().unwrap()
+}
+
+// Send a request and read up to its reply; returns the reply and the session
+// token, if the server pushed one meanwhile
+fn exchange(stream: &mut TcpStream, request: Frame) -> (Frame, Option<String>) {
+    write_frame(stream, &request).unwrap();
+    let mut token = None;
+    loop {
+        let frame = read_frame(stream).unwrap().expect("connection closed");
+        if frame.opcode == OP_SESSION {
+            token = Some(frame.text());
+        } else if frame.id == request.id {
+            return (frame, token);
+        }
+    }
+}
+
+fn connect(addr: SocketAddr) -> TcpStream {
+    let stream = TcpStream::connect(addr).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    stream
+}
+
+#[test]
+fn resumed_client_keeps_its_id_and_counters() {
+    let server = start_server();
+    let addr = server.local_addr();
+
+    let mut stream = connect(addr);
+    let (reply, token) = exchange(&mut stream, Frame::new(OP_UPPER, "abc").with_id(1));
+    assert_eq!(reply.text(), "ABC");
+    let token = token.expect("no session token");
+    exchange(&mut stream, Frame::new(OP_COUNT, "").with_id(2));
+    let [(client_id, ref stats)] = server.clients()[..] else {
+        panic!("expected one client, got {:?}", server.clients());
+    };
+    assert_eq!(stats.requests, 2);
+    drop(stream);
+
+    // Resume as soon as the server has parked the session, to race the old
+    // connection's cleanup
+    let mut stream = loop {
+        let mut stream = connect(addr);
+        let (reply, _) = exchange(&mut stream, Frame::new(OP_RESUME, token.as_str()).with_id(1));
+        if reply.opcode == OP_REPLY {
+            assert_eq!(reply.text(), format!("resumed as client {}", client_id));
+            break stream;
+        }
+        // Not parked yet: leave for good and try again
+        write_frame(&mut stream, &Frame::new(OP_QUIT, "").with_id(2)).unwrap();
+        thread::sleep(Duration::from_millis(1));
+    };
+    // Give the old connection and the failed attempts time to be cleaned up
+    thread::sleep(Duration::from_millis(200));
+    let clients = server.clients();
+    assert_eq!(clients.len(), 1, "{:?}", clients);
+    assert_eq!(clients[0].0, client_id);
+    assert_eq!(clients[0].1.requests, 2);
+
+    exchange(&mut stream, Frame::new(OP_COUNT, "").with_id(2));
+    assert_eq!(server.clients()[0].1.requests, 3);
+
+    drop(stream);
+    server.shutdown().unwrap();
+}
+
+#[test]
+fn kicked_client_cannot_resume() {
+    let server = start_server();
+    let addr = server.local_addr();
+
+    let mut stream = connect(addr);
+    let (_, token) = exchange(&mut stream, Frame::new(OP_COUNT, "").with_id(1));
+    let token = token.expect("no session token");
+    let client_id = server.clients()[0].0;
+    assert!(server.kick(client_id));
+
+    let kick = read_frame(&mut stream).unwrap().expect("no kick push");
+    assert!(kick.is_push());
+    assert_eq!(kick.opcode, OP_KICK);
+    assert!(matches!(read_frame(&mut stream), Ok(None) | Err(_)), "still connected after the kick");
+
+    thread::sleep(Duration::from_millis(100));
+    let mut stream = connect(addr);
+    let (reply, _) = exchange(&mut stream, Frame::new(OP_RESUME, token.as_str()).with_id(1));
+    assert_eq!(reply.opcode, OP_ERROR);
+
+    drop(stream);
+    server.shutdown().unwrap();
+}
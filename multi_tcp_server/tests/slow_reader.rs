@@ -0,0 +1,115 @@
+// A client that has stopped reading: its replies fill the socket buffers, and
+// writing to it must not hold up the shutdown, a kick or a broadcast
+
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use multi_tcp_server::config::Config;
+use multi_tcp_server::logger::Level;
+use multi_tcp_server::protocol::{read_frame, write_frame, Frame, OP_NOTICE, OP_UPPER};
+use multi_tcp_server::{Server, ServerHandle};
+
+const GRACE_PERIOD: Duration = Duration::from_millis(500);
+
+fn start_server(async_io: bool) -> ServerHandle {
+    let config = Config { log_level: Level::Error, async_io, ..Config::default() };
+    Server::builder()
+        .config(config)
+        .grace_period(GRACE_PERIOD)
+        .bind("127.0.0.1:0".parse().unwrap())
+        .start()
+        .unwrap()
+}
+
+// Send large requests and never read a reply. The server drops the client once a
+// reply has waited for WRITE_TIMEOUT, the tests are done well before that.
+fn stop_reading(addr: SocketAddr) -> TcpStream {
+    let stream = TcpStream::connect(addr).unwrap();
+    stream.set_write_timeout(Some(Duration::from_millis(500))).unwrap();
+    let mut writer = stream.try_clone().unwrap();
+    thread::spawn(move || {
+        let text = "a".repeat(1024 * 1024);
+        for id in 1..=64 {
+            if write_frame(&mut writer, &Frame::new(OP_UPPER, text.as_str()).with_id(id)).is_err() {
+                break;
+            }
+        }
+    });
+    // Let the replies pile up
+    thread::sleep(Duration::from_millis(300));
+    stream
+}
+
+fn shutdown_is_not_held_up(async_io: bool) {
+    let server = start_server(async_io);
+    let _stuck = stop_reading(server.local_addr());
+
+    let start = Instant::now();
+    server.shutdown().unwrap();
+    let elapsed = start.elapsed();
+    // The grace period and the forced close, nothing waits on the stuck client
+    assert!(elapsed < GRACE_PERIOD + Duration::from_secs(2), "shutdown took {:?}", elapsed);
+}
+
+#[test]
+fn client_that_stopped_reading_does_not_hold_up_shutdown() {
+    shutdown_is_not_held_up(false);
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn client_that_stopped_reading_does_not_hold_up_async_shutdown() {
+    shutdown_is_not_held_up(true);
+}
+
+// A client that reads, after its first request has been answered
+fn reading_client(addr: SocketAddr) -> TcpStream {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    write_frame(&mut stream, &Frame::new(OP_UPPER, "abc").with_id(1)).unwrap();
+    while read_frame(&mut stream).unwrap().expect("connection closed").id != 1 {}
+    stream
+}
+
+#[test]
+fn kicking_a_client_that_stopped_reading_returns_at_once() {
+    let server = start_server(false);
+    let _stuck = stop_reading(server.local_addr());
+    let [(id, _)] = server.clients()[..] else {
+        panic!("expected one client, got {:?}", server.clients());
+    };
+
+    let start = Instant::now();
+    assert!(server.kick(id));
+    assert!(start.elapsed() < Duration::from_millis(500), "kick took {:?}", start.elapsed());
+    // The connection is closed even though the notice cannot get through
+    let deadline = Instant::now() + Duration::from_secs(3);
+    while !server.clients().is_empty() {
+        assert!(Instant::now() < deadline, "kicked client still connected");
+        thread::sleep(Duration::from_millis(50));
+    }
+    server.shutdown().unwrap();
+}
+
+#[test]
+fn broadcast_reaches_others_despite_a_client_that_stopped_reading() {
+    let server = start_server(false);
+    let mut reader = reading_client(server.local_addr());
+    let _stuck = stop_reading(server.local_addr());
+
+    let start = Instant::now();
+    let (delivered, clients) = server.broadcast("hello");
+    // The stuck client holds the broadcast up for NOTICE_WAIT at most
+    assert!(start.elapsed() < Duration::from_secs(2), "broadcast took {:?}", start.elapsed());
+    assert_eq!(clients, 2);
+    assert!(delivered >= 1);
+    loop {
+        let frame = read_frame(&mut reader).unwrap().expect("connection closed");
+        if frame.opcode == OP_NOTICE {
+            assert_eq!(frame.text(), "hello");
+            break;
+        }
+    }
+    server.shutdown().unwrap();
+}
@@ -0,0 +1,116 @@
+// TLS end to end: the server on a fresh certificate, the real client on the other
+// side trusting it, and client certificates when the server requires them
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+use multi_tcp_client::{config::Config as ClientConfig, OptClient};
+use multi_tcp_server::config::Config;
+use multi_tcp_server::logger::Level;
+use multi_tcp_server::tls::TlsSettings;
+use multi_tcp_server::{Server, ServerHandle};
+use rcgen::{BasicConstraints, CertificateParams, IsCa, KeyPair};
+
+// A CA and a certificate it signed for "localhost", all in PEM form
+struct Pki {
+    ca: String,
+    cert: String,
+    key: String,
+}
+
+fn pki() -> Pki {
+    let ca_key = KeyPair::generate().unwrap();
+    let mut ca_params = CertificateParams::new(Vec::new()).unwrap();
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    let ca = ca_params.self_signed(&ca_key).unwrap();
+    let key = KeyPair::generate().unwrap();
+    let cert = CertificateParams::new(vec!["localhost".to_string()]).unwrap().signed_by(&key, &ca, &ca_key).unwrap();
+    Pki { ca: ca.pem(), cert: cert.pem(), key: key.serialize_pem() }
+}
+
+// A directory for the PEM files of one test, which the client reads its
+// certificates from; removed with everything in it when the test ends
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new(test: &str) -> TempDir {
+        let path = env::temp_dir().join(format!("multi_tcp_server-tls-{}-{}", std::process::id(), test));
+        fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    fn write_pem(&self, name: &str, pem: &str) -> PathBuf {
+        let path = self.0.join(format!("{}.pem", name));
+        fs::write(&path, pem).unwrap();
+        path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+fn start_server(settings: TlsSettings) -> ServerHandle {
+    let config = Config { log_level: Level::Error, ..Config::default() };
+    Server::builder().config(config).bind("127.0.0.1:0".parse().unwrap()).tls(settings).start().unwrap()
+}
+
+fn client_config(ca: PathBuf) -> ClientConfig {
+    ClientConfig { tls: true, tls_ca: Some(ca), ..ClientConfig::default() }
+}
+
+#[test]
+fn client_talks_to_tls_server() {
+    let server_pki = pki();
+    let settings = TlsSettings::from_pem(server_pki.cert.as_bytes(), server_pki.key.as_bytes(), None).unwrap();
+    let server = start_server(settings);
+
+    let dir = TempDir::new("client-talks");
+    let config = client_config(dir.write_pem("server-ca", &server_pki.ca));
+    let mut client = OptClient::builder().config(config).connect(server.local_addr()).unwrap();
+    assert_eq!(client.to_upper("secret").unwrap(), "SECRET");
+    assert_eq!(client.whoami().unwrap().ip(), server.local_addr().ip());
+    client.close().unwrap();
+    server.shutdown().unwrap();
+}
+
+#[test]
+fn client_certificate_is_required_with_client_ca() {
+    let server_pki = pki();
+    let client_pki = pki();
+    let settings = TlsSettings::from_pem(
+        server_pki.cert.as_bytes(),
+        server_pki.key.as_bytes(),
+        Some(client_pki.ca.as_bytes()),
+    )
+    .unwrap();
+    let server = start_server(settings);
+    let dir = TempDir::new("client-auth");
+    let server_ca = dir.write_pem("server-ca", &server_pki.ca);
+
+    // No certificate: the handshake fails, at the latest when the first reply is due
+    let result = OptClient::builder().config(client_config(server_ca.clone())).connect(server.local_addr());
+    assert!(result.is_err(), "connected without a client certificate");
+
+    // A certificate from some other CA is no better
+    let stranger = pki();
+    let config = ClientConfig {
+        tls_cert: Some(dir.write_pem("stranger-cert", &stranger.cert)),
+        tls_key: Some(dir.write_pem("stranger-key", &stranger.key)),
+        ..client_config(server_ca.clone())
+    };
+    assert!(OptClient::builder().config(config).connect(server.local_addr()).is_err());
+
+    let config = ClientConfig {
+        tls_cert: Some(dir.write_pem("client-cert", &client_pki.cert)),
+        tls_key: Some(dir.write_pem("client-key", &client_pki.key)),
+        ..client_config(server_ca)
+    };
+    let mut client = OptClient::builder().config(config).connect(server.local_addr()).unwrap();
+    assert_eq!(client.to_upper("signed").unwrap(), "SIGNED");
+    client.close().unwrap();
+    server.shutdown().unwrap();
+}